@@ -0,0 +1,348 @@
+//! Animations: values keyframed over time, objects and cameras following them, and the rendering
+//! of frame sequences in which each frame has its own shutter interval. Times are in seconds.
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, ImageResult, RgbImage};
+use log::info;
+
+use crate::aabb::{surrounding_box, Aabb};
+use crate::camera::Camera;
+use crate::environment::Environment;
+use crate::film::Film;
+use crate::hitable::{HitRecord, Hitable};
+use crate::random::seeded_rng;
+use crate::ray::Ray;
+use crate::render::{render, RenderSettings};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec::Vec3;
+
+/// Values which can be interpolated between keyframes.
+pub trait Lerp: Copy {
+    /// Return the value a fraction `t` of the way from `a` to `b`.
+    fn lerp(a: Self, b: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(a: f32, b: f32, t: f32) -> f32 {
+        a + t * (b - a)
+    }
+}
+
+impl Lerp for Vec3 {
+    fn lerp(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+        a + t * (b - a)
+    }
+}
+
+/// A value interpolated linearly between keyframes, and held before the first one and after the
+/// last one.
+#[derive(Debug, Clone)]
+pub struct Keyframes<T> {
+    /// Times and values of the keyframes, by increasing time
+    keys: Vec<(f32, T)>,
+}
+
+impl<T: Lerp> Keyframes<T> {
+    /// Create keyframes from pairs of times and values, in any order. There must be at least one.
+    pub fn new(mut keys: Vec<(f32, T)>) -> Keyframes<T> {
+        assert!(!keys.is_empty(), "no keyframes");
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Keyframes { keys }
+    }
+
+    /// A value which doesn't change.
+    pub fn constant(value: T) -> Keyframes<T> {
+        Keyframes {
+            keys: vec![(0.0, value)],
+        }
+    }
+
+    /// Return the value at the given time.
+    pub fn at(&self, time: f32) -> T {
+        let next = self.keys.partition_point(|&(t, _)| t <= time);
+        if next == 0 {
+            return self.keys[0].1;
+        }
+        if next == self.keys.len() {
+            return self.keys[next - 1].1;
+        }
+        let (t0, a) = self.keys[next - 1];
+        let (t1, b) = self.keys[next];
+        T::lerp(a, b, (time - t0) / (t1 - t0))
+    }
+
+    /// Return the values at `time0` and `time1` and at the keyframes in between, which include
+    /// the extremes of the value over that interval.
+    fn extremes(&self, time0: f32, time1: f32) -> Vec<T> {
+        let inner = self
+            .keys
+            .iter()
+            .filter(|&&(t, _)| t > time0 && t < time1)
+            .map(|&(_, value)| value);
+        [self.at(time0), self.at(time1)]
+            .into_iter()
+            .chain(inner)
+            .collect()
+    }
+}
+
+/// Wrapper moving an object by an offset keyframed over time.
+pub struct Animated {
+    ptr: Arc<dyn Hitable>,
+    offset: Keyframes<Vec3>,
+}
+
+impl Animated {
+    pub fn new(ptr: Arc<dyn Hitable>, offset: Keyframes<Vec3>) -> Animated {
+        Animated { ptr, offset }
+    }
+}
+
+impl Hitable for Animated {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let offset = self.offset.at(r.time());
+        let moved = r.spawn(&(*r.origin() - offset), r.direction());
+        if self.ptr.hit(&moved, t_min, t_max, rec) {
+            rec.p += offset;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32, aabb: &mut Aabb) -> bool {
+        let mut child = Aabb::default();
+        if !self.ptr.bounding_box(t0, t1, &mut child) {
+            return false;
+        }
+        // The offset is linear between keyframes, so the box is swept between its extremes
+        let mut boxes = self
+            .offset
+            .extremes(t0, t1)
+            .into_iter()
+            .map(|offset| Aabb::new(&(child.min + offset), &(child.max + offset)));
+        let first = boxes.next().unwrap();
+        *aabb = boxes.fold(first, |a, b| surrounding_box(&a, &b));
+        true
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.ptr.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        self.ptr.pdf_value(&(*o - self.offset.at(time)), v, time)
+    }
+
+    fn random(&self, o: &Vec3, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        self.ptr.random(&(*o - self.offset.at(time)), time, sampler)
+    }
+}
+
+/// Camera parameters which can be keyframed, from which the camera of each frame is set up.
+#[derive(Debug, Clone)]
+pub struct CameraRig {
+    pub lookfrom: Keyframes<Vec3>,
+    pub lookat: Keyframes<Vec3>,
+    pub vup: Vec3,
+    /// Vertical field of view, in degrees
+    pub fov: Keyframes<f32>,
+    pub aperture: f32,
+    pub focus_distance: Keyframes<f32>,
+}
+
+impl CameraRig {
+    /// Return the camera of an image with the given aspect ratio, whose shutter is open from
+    /// `time0` to `time1`. The camera stays where the rig is in the middle of that interval, so
+    /// only objects are motion blurred.
+    pub fn camera(&self, aspect: f32, time0: f32, time1: f32) -> Camera {
+        let time = 0.5 * (time0 + time1);
+        Camera::new(
+            self.lookfrom.at(time),
+            self.lookat.at(time),
+            self.vup,
+            self.fov.at(time),
+            aspect,
+            self.aperture,
+            time0,
+            time1,
+            self.focus_distance.at(time),
+        )
+    }
+}
+
+/// The frames of an animation, and how long the shutter stays open during each of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Animation {
+    /// Number of frames to render
+    pub frames: usize,
+    /// Number of frames per second
+    pub frame_rate: f32,
+    /// Time at which the first frame starts
+    pub start: f32,
+    /// Fraction of each frame during which the shutter is open, e.g. 0.5 for a 180° shutter. With
+    /// 0, frames show a single instant, without motion blur
+    pub shutter: f32,
+}
+
+impl Default for Animation {
+    fn default() -> Animation {
+        Animation {
+            frames: 48,
+            frame_rate: 24.0,
+            start: 0.0,
+            shutter: 0.5,
+        }
+    }
+}
+
+impl Animation {
+    /// Return the times at which the shutter opens and closes for the given frame.
+    pub fn shutter_interval(&self, frame: usize) -> (f32, f32) {
+        let time0 = self.start + frame as f32 / self.frame_rate;
+        (time0, time0 + self.shutter / self.frame_rate)
+    }
+}
+
+/// Render the frames of an animation of `objects` seen through `rig`, one after the other, and
+/// call `on_frame` with the index and film of each frame. Each frame is rendered with its own
+/// seed, so that the noise isn't the same in every frame. Checkpoints aren't saved.
+pub fn render_animation<F>(
+    objects: &[Arc<dyn Hitable>],
+    environment: &Arc<dyn Environment>,
+    rig: &CameraRig,
+    settings: &RenderSettings,
+    animation: &Animation,
+    mut on_frame: F,
+) where
+    F: FnMut(usize, Film),
+{
+    let aspect = settings.width as f32 / settings.height as f32;
+    for frame in 0..animation.frames {
+        let (time0, time1) = animation.shutter_interval(frame);
+        info!(
+            "Rendering frame {}/{} ({:.3}s to {:.3}s)",
+            frame + 1,
+            animation.frames,
+            time0,
+            time1
+        );
+        let settings = RenderSettings {
+            seed: settings.seed.wrapping_add(frame as u64),
+            checkpoint: None,
+            ..settings.clone()
+        };
+        // The bounding boxes of moving objects only need to cover the shutter interval
+        let mut rng = seeded_rng(settings.seed);
+        let scene = Scene::new(
+            objects.to_vec(),
+            environment.clone(),
+            time0,
+            time1,
+            &mut rng,
+        );
+        let camera = rig.camera(aspect, time0, time1);
+        on_frame(frame, render(&scene, &camera, &settings));
+    }
+}
+
+/// Write the given frames to an animated GIF which loops forever.
+pub fn save_gif<P: AsRef<Path>>(path: P, frames: &[RgbImage], frame_rate: f32) -> ImageResult<()> {
+    let out = BufWriter::new(File::create(path)?);
+    let mut encoder = GifEncoder::new_with_speed(out, 10);
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_saturating_duration(Duration::from_secs_f32(1.0 / frame_rate));
+    encoder.encode_frames(frames.iter().map(|frame| {
+        let rgba = DynamicImage::ImageRgb8(frame.clone()).into_rgba8();
+        Frame::from_parts(rgba, 0, 0, delay)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::Sphere;
+    use crate::material::Lambertian;
+
+    #[test]
+    fn keyframes_interpolate_linearly_and_hold_outside() {
+        // Keyframes can be given in any order
+        let keys = Keyframes::new(vec![(3.0, 10.0), (1.0, 2.0), (2.0, 4.0)]);
+        assert_eq!(keys.at(0.0), 2.0);
+        assert_eq!(keys.at(1.0), 2.0);
+        assert_eq!(keys.at(1.5), 3.0);
+        assert_eq!(keys.at(2.0), 4.0);
+        assert_eq!(keys.at(2.25), 5.5);
+        assert_eq!(keys.at(3.0), 10.0);
+        assert_eq!(keys.at(7.0), 10.0);
+        assert_eq!(Keyframes::constant(5.0).at(-1.0), 5.0);
+
+        let offsets = Keyframes::new(vec![
+            (0.0, Vec3::default()),
+            (2.0, Vec3::new(2.0, -4.0, 6.0)),
+        ]);
+        assert_eq!(offsets.at(0.5), Vec3::new(0.5, -1.0, 1.5));
+    }
+
+    #[test]
+    fn extremes_include_keyframes_within_interval() {
+        let keys = Keyframes::new(vec![(0.0, 0.0), (1.0, 5.0), (2.0, -1.0), (3.0, 0.0)]);
+        let mut extremes = keys.extremes(0.5, 2.5);
+        extremes.sort_by(f32::total_cmp);
+        assert_eq!(extremes, [-1.0, -0.5, 2.5, 5.0]);
+        assert_eq!(keys.extremes(1.25, 1.75).len(), 2);
+    }
+
+    #[test]
+    fn animated_objects_follow_their_keyframes() {
+        let material = Arc::new(Lambertian::constant(Vec3::new(0.5, 0.5, 0.5)));
+        let sphere = Arc::new(Sphere::new(Vec3::default(), 1.0, material));
+        let offset = Keyframes::new(vec![
+            (0.0, Vec3::default()),
+            (1.0, Vec3::new(4.0, 0.0, 0.0)),
+        ]);
+        let animated = Animated::new(sphere, offset);
+
+        // The sphere is at x = 2 halfway through
+        let origin = Vec3::new(2.0, 0.0, -5.0);
+        let direction = Vec3::new(0.0, 0.0, 1.0);
+        let mut rec = HitRecord::default();
+        assert!(animated.hit(
+            &Ray::with_time(&origin, &direction, 0.5),
+            0.0,
+            100.0,
+            &mut rec
+        ));
+        assert!((rec.p - Vec3::new(2.0, 0.0, -1.0)).length() < 1e-5);
+        assert!(!animated.hit(
+            &Ray::with_time(&origin, &direction, 0.0),
+            0.0,
+            100.0,
+            &mut rec
+        ));
+
+        let mut aabb = Aabb::default();
+        assert!(animated.bounding_box(0.25, 0.75, &mut aabb));
+        assert_eq!(aabb.min, Vec3::new(0.0, -1.0, -1.0));
+        assert_eq!(aabb.max, Vec3::new(4.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn frames_have_their_own_shutter_interval() {
+        let animation = Animation {
+            frames: 10,
+            frame_rate: 25.0,
+            start: 1.0,
+            shutter: 0.5,
+        };
+        assert_eq!(animation.shutter_interval(0), (1.0, 1.02));
+        let (time0, time1) = animation.shutter_interval(5);
+        assert!((time0 - 1.2).abs() < 1e-6 && (time1 - 1.22).abs() < 1e-6);
+    }
+}
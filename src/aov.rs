@@ -0,0 +1,190 @@
+//! Arbitrary output variables: buffers describing the first surface seen through each pixel,
+//! written alongside the rendered image for compositing and denoising.
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::hitable::HitRecord;
+use crate::random::mix_bits;
+use crate::ray::Ray;
+use crate::vec::{unit_vector, Vec3};
+
+/// The different buffers which can be rendered. Rays which don't hit anything give zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Aov {
+    /// Albedo of the material
+    Albedo,
+    /// Unit normal, in world space
+    Normal,
+    /// Distance from the camera
+    Depth,
+    /// Position, in world space
+    Position,
+    /// Texture coordinates
+    Uv,
+    /// Index of the object in the list the scene was created from, plus one
+    ObjectId,
+    /// Number of samples taken in the pixel, which varies with adaptive sampling
+    SampleCount,
+}
+
+impl Aov {
+    /// All the buffers which can be rendered.
+    pub const ALL: [Aov; 7] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Uv,
+        Aov::ObjectId,
+        Aov::SampleCount,
+    ];
+
+    /// Return the buffer with the given name, as returned by `name`.
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().copied().find(|aov| aov.name() == name)
+    }
+
+    /// Name of the buffer, used for file names and EXR channels.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Uv => "uv",
+            Aov::ObjectId => "id",
+            Aov::SampleCount => "samples",
+        }
+    }
+
+    /// Names of the channels holding the buffer's values.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::Uv => &["U", "V"],
+            Aov::ObjectId => &["id"],
+            Aov::SampleCount => &["count"],
+        }
+    }
+
+    /// Whether the values of a pixel's samples are averaged. Otherwise the pixel takes the value
+    /// of its first sample, which makes sense for ids.
+    pub fn is_averaged(&self) -> bool {
+        *self != Aov::ObjectId
+    }
+
+    /// Return the value of the buffer for the camera ray `r`, which hit the surface described by
+    /// `rec` if any. Sample counts don't depend on the ray, and are filled in by the film.
+    pub fn value(&self, r: &Ray, rec: Option<&HitRecord>) -> Vec3 {
+        let rec = match rec {
+            Some(rec) => rec,
+            None => return Vec3::default(),
+        };
+        match self {
+            Aov::Albedo => rec
+                .mat
+                .as_ref()
+                .map_or(Vec3::default(), |mat| mat.albedo(rec)),
+            Aov::Normal => unit_vector(&rec.normal),
+            Aov::Depth => {
+                let depth = rec.t * r.direction().length();
+                Vec3::new(depth, depth, depth)
+            }
+            Aov::Position => rec.p,
+            Aov::Uv => Vec3::new(rec.u, rec.v, 0.0),
+            Aov::ObjectId => {
+                let id = rec.object_id as f32;
+                Vec3::new(id, id, id)
+            }
+            Aov::SampleCount => Vec3::default(),
+        }
+    }
+
+    /// Convert a value of the buffer to an 8-bit colour, for formats which can't store it
+    /// directly. Normals are remapped from [-1, 1], ids get a random colour, sample counts are
+    /// shown as a heatmap relative to `max`, the largest value in the buffer, and other values are
+    /// clamped to [0, 1].
+    pub fn ldr_color(&self, value: &Vec3, max: f32) -> [u8; 3] {
+        let value = match self {
+            Aov::Normal => 0.5 * (*value + Vec3::new(1.0, 1.0, 1.0)),
+            Aov::ObjectId if value.r() > 0.0 => {
+                let hash = mix_bits(value.r() as u64);
+                Vec3::new(
+                    (hash & 0xff) as f32 / 255.0,
+                    ((hash >> 8) & 0xff) as f32 / 255.0,
+                    ((hash >> 16) & 0xff) as f32 / 255.0,
+                )
+            }
+            Aov::SampleCount if max > 0.0 => heatmap(value.r() / max),
+            _ => *value,
+        };
+
+        let mut rgb = [0; 3];
+        for (i, v) in rgb.iter_mut().enumerate() {
+            *v = (255.0 * value[i].clamp(0.0, 1.0) + 0.5) as u8;
+        }
+        rgb
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Aov, String> {
+        Aov::from_name(name).ok_or_else(|| format!("unknown AOV `{}`", name))
+    }
+}
+
+impl TryFrom<String> for Aov {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Aov, String> {
+        name.parse()
+    }
+}
+
+/// Map `t` in [0, 1] to a colour going from black through blue, cyan, green and yellow to red.
+fn heatmap(t: f32) -> Vec3 {
+    const COLORS: [[f32; 3]; 6] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+    ];
+    let x = t.clamp(0.0, 1.0) * (COLORS.len() - 1) as f32;
+    let i = usize::min(x as usize, COLORS.len() - 2);
+    let f = x - i as f32;
+    let (a, b) = (COLORS[i], COLORS[i + 1]);
+    Vec3::new(
+        a[0] + f * (b[0] - a[0]),
+        a[1] + f * (b[1] - a[1]),
+        a[2] + f * (b[2] - a[2]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aovs_are_parsed_from_their_names() {
+        for aov in Aov::ALL {
+            assert_eq!(aov.name().parse(), Ok(aov));
+        }
+        assert!("normals".parse::<Aov>().is_err());
+
+        #[derive(Deserialize)]
+        struct Settings {
+            aovs: Vec<Aov>,
+        }
+        let settings: Settings = toml::from_str("aovs = [\"normal\", \"id\"]").unwrap();
+        assert_eq!(settings.aovs, [Aov::Normal, Aov::ObjectId]);
+        assert!(toml::from_str::<Settings>("aovs = [\"normals\"]").is_err());
+    }
+}
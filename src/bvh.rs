@@ -5,6 +5,7 @@ use rand::Rng;
 
 use crate::aabb::{surrounding_box, Aabb};
 use crate::hitable::{HitRecord, Hitable};
+use crate::random::RenderRng;
 use crate::ray::Ray;
 
 pub struct BvhNode {
@@ -14,8 +15,7 @@ pub struct BvhNode {
 }
 
 impl BvhNode {
-    pub fn new(l: &mut [Arc<dyn Hitable>], time0: f32, time1: f32) -> BvhNode {
-        let mut rng = rand::thread_rng();
+    pub fn new(l: &mut [Arc<dyn Hitable>], time0: f32, time1: f32, rng: &mut RenderRng) -> BvhNode {
         let axis = (rng.gen::<f32>() * 3.0) as usize;
         match axis {
             0 => l.sort_by(box_x_compare),
@@ -31,16 +31,16 @@ impl BvhNode {
             let len = l.len();
             let (left_list, right_list) = l.split_at_mut(len / 2);
             (
-                Arc::new(BvhNode::new(left_list, time0, time1)) as Arc<dyn Hitable>,
-                Arc::new(BvhNode::new(right_list, time0, time1)) as Arc<dyn Hitable>,
+                Arc::new(BvhNode::new(left_list, time0, time1, rng)) as Arc<dyn Hitable>,
+                Arc::new(BvhNode::new(right_list, time0, time1, rng)) as Arc<dyn Hitable>,
             )
         };
 
         let mut box_left = Aabb::default();
         let mut box_right = Aabb::default();
 
-        if !left.bounding_box(0.0, 0.0, &mut box_left)
-            || !right.bounding_box(0.0, 0.0, &mut box_right)
+        if !left.bounding_box(time0, time1, &mut box_left)
+            || !right.bounding_box(time0, time1, &mut box_right)
         {
             panic!("No bounding box in BvhNode constructor!");
         }
@@ -1,9 +1,8 @@
 use std::f32;
 
-use rand::{self, Rng};
-
 use crate::ray::Ray;
-use crate::vec::{cross, dot, unit_vector, Vec3};
+use crate::sampler::Sampler;
+use crate::vec::{cross, unit_vector, Vec3};
 
 pub struct Camera {
     origin: Vec3,
@@ -58,25 +57,22 @@ impl Camera {
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius * random_in_unit_disk(sampler);
         let offset = &self.u * rd.x() + &self.v * rd.y();
         Ray::with_time(
             &(self.origin + offset),
             &(self.lower_left_corner + s * self.horizontal + t * self.vertical
                 - self.origin
                 - offset),
-            self.time0 + rand::thread_rng().gen::<f32>() * (self.time1 - self.time0),
+            self.time0 + sampler.get_1d() * (self.time1 - self.time0),
         )
     }
 }
 
-fn random_in_unit_disk() -> Vec3 {
-    let mut rng = rand::thread_rng();
-    loop {
-        let p = 2.0 * Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), 0.0) - Vec3::new(1.0, 1.0, 0.0);
-        if dot(&p, &p) < 1.0 {
-            return p;
-        }
-    }
+fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.get_2d();
+    let r = f32::sqrt(u1);
+    let theta = 2.0 * f32::consts::PI * u2;
+    Vec3::new(r * f32::cos(theta), r * f32::sin(theta), 0.0)
 }
@@ -0,0 +1,175 @@
+//! Checkpoints of progressive renders, from which an interrupted render can be resumed. A
+//! checkpoint holds the raw film, so resuming gives the same image as an uninterrupted render. It
+//! also holds the fingerprint of the render, so that only the same render can resume from it.
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::film::Film;
+
+/// Identifies checkpoint files, followed by the version of the format.
+const MAGIC: &[u8; 8] = b"RTIOWCKP";
+const VERSION: u32 = 2;
+
+/// The state of a progressive render after some number of passes.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// The samples accumulated so far
+    pub film: Film,
+    /// Number of passes rendered into the film
+    pub passes: usize,
+    /// Fingerprint of the render, see `Renderer::fingerprint`, which must match to resume it
+    pub fingerprint: u64,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint saved at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Checkpoint> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        let mut word = [0u8; 4];
+        input.read_exact(&mut word)?;
+        if &magic != MAGIC || u32::from_le_bytes(word) != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a checkpoint, or from an incompatible version",
+            ));
+        }
+
+        input.read_exact(&mut word)?;
+        let passes = u32::from_le_bytes(word) as usize;
+        let mut fingerprint = [0u8; 8];
+        input.read_exact(&mut fingerprint)?;
+        let fingerprint = u64::from_le_bytes(fingerprint);
+        let film = Film::read_state(&mut input)?;
+
+        Ok(Checkpoint {
+            film,
+            passes,
+            fingerprint,
+        })
+    }
+}
+
+/// Save the state of a render with the given fingerprint to `path`. The checkpoint is written to
+/// a temporary file first, so a crash while saving doesn't destroy the previous checkpoint.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    film: &Film,
+    passes: usize,
+    fingerprint: u64,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(passes as u32).to_le_bytes())?;
+    out.write_all(&fingerprint.to_le_bytes())?;
+    film.write_state(&mut out)?;
+    out.into_inner()?.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::aov::Aov;
+    use crate::film::write_u32;
+    use crate::filter::FilterKind;
+    use crate::render::Tile;
+    use crate::vec::Vec3;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rtiow-{}-{}.ckp", std::process::id(), name))
+    }
+
+    /// Return a small film with a few samples in some pixels.
+    fn test_film() -> Film {
+        let mut film = Film::new(5, 3, &[Aov::Normal, Aov::ObjectId]);
+        let filter = FilterKind::Tent.create(1.0);
+        let tile = Tile {
+            x0: 0,
+            y0: 0,
+            x1: 5,
+            y1: 3,
+        };
+        let mut film_tile = film.tile(&tile, filter.radius());
+        for (i, (x, y)) in [(0, 0), (2, 1), (2, 1), (4, 2)].into_iter().enumerate() {
+            let l = Vec3::new(i as f32, 0.5, 2.0);
+            let (u, v) = (x as f32 + 0.3, y as f32 + 0.6);
+            film_tile.add_sample(u, v, &l, &*filter);
+            let values = [
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(i as f32, i as f32, i as f32),
+            ];
+            film_tile.add_pixel_sample(x, y, &l, &values);
+        }
+        film.add_tile(&film_tile);
+        film
+    }
+
+    fn open_error(name: &str, bytes: &[u8]) -> io::ErrorKind {
+        let path = temp_path(name);
+        fs::write(&path, bytes).unwrap();
+        let result = Checkpoint::open(&path);
+        fs::remove_file(path).unwrap();
+        result.unwrap_err().kind()
+    }
+
+    #[test]
+    fn checkpoints_round_trip() {
+        let path = temp_path("round-trip");
+        let film = test_film();
+        save(&path, &film, 3, 0x1234_5678_9abc_def0).unwrap();
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(checkpoint.passes, 3);
+        assert_eq!(checkpoint.fingerprint, 0x1234_5678_9abc_def0);
+        let read = checkpoint.film;
+        assert_eq!(read.pixels(), film.pixels());
+        assert_eq!(read.variances(), film.variances());
+        assert_eq!(read.aovs(), film.aovs());
+        for &aov in film.aovs() {
+            assert_eq!(read.aov_pixels(aov), film.aov_pixels(aov));
+        }
+        for (x, y) in [(0, 0), (2, 1), (3, 2)] {
+            assert_eq!(read.sample_count(x, y), film.sample_count(x, y));
+            assert_eq!(read.mean_luminance(x, y), film.mean_luminance(x, y));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_checkpoints() {
+        let header = |film: &[u32]| {
+            let mut bytes = MAGIC.to_vec();
+            for word in [VERSION, 1, 0, 0].iter().chain(film) {
+                write_u32(&mut bytes, *word).unwrap();
+            }
+            bytes
+        };
+        assert_eq!(
+            open_error("magic", b"RTIOWIMG\x02\0\0\0"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            open_error("truncated", &header(&[2, 2, 0, 0])),
+            io::ErrorKind::UnexpectedEof
+        );
+        // Too many pixels, whose number also overflows on 32-bit targets
+        let large = header(&[1 << 16, 1 << 16, 0]);
+        assert_eq!(open_error("large", &large), io::ErrorKind::InvalidData);
+        let many_aovs = header(&[2, 2, 1000]);
+        assert_eq!(open_error("aovs", &many_aovs), io::ErrorKind::InvalidData);
+        let long_name = header(&[2, 2, 1, u32::MAX]);
+        assert_eq!(open_error("name", &long_name), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,305 @@
+//! Edge-avoiding à-trous wavelet denoiser (Dammertz et al., with the variance-guided weights of
+//! Schied et al.'s SVGF). The image is blurred several times with an increasingly sparse 5x5
+//! kernel, whose weights drop across discontinuities of the normal and depth buffers and across
+//! luminance differences which can't be explained by the estimated noise.
+use rayon::prelude::*;
+
+use crate::aov::Aov;
+use crate::film::Film;
+use crate::vec::{dot, unit_vector, Vec3};
+
+/// The AOVs used to guide the denoiser.
+pub const GUIDE_AOVS: [Aov; 3] = [Aov::Albedo, Aov::Normal, Aov::Depth];
+
+/// Number of passes of the filter. The kernel's footprint doubles with each pass.
+const ITERATIONS: u32 = 5;
+
+/// Weights of the B3-spline kernel along each axis.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Falloffs of the edge-stopping functions.
+const SIGMA_LUMINANCE: f32 = 4.0;
+const SIGMA_NORMAL: i32 = 128;
+const SIGMA_DEPTH: f32 = 0.05;
+
+/// Smallest albedo by which the image gets divided.
+const MIN_ALBEDO: f32 = 0.01;
+
+/// Per-pixel data guiding the filter.
+struct Guides {
+    width: usize,
+    height: usize,
+    normal: Option<Vec<Vec3>>,
+    depth: Option<Vec<Vec3>>,
+}
+
+impl Guides {
+    /// Return the weight between pixels `p` and `q`, `distance` pixels apart, based on their
+    /// normals and depths.
+    fn weight(&self, p: usize, q: usize, distance: f32) -> f32 {
+        if p == q {
+            return 1.0;
+        }
+        let mut weight = 1.0;
+        if let Some(normal) = &self.normal {
+            // Pixels where nothing was hit have no normal, and only match each other
+            let (np, nq) = (normal[p], normal[q]);
+            let no_hit = Vec3::default();
+            if np == no_hit || nq == no_hit {
+                if np != nq {
+                    return 0.0;
+                }
+            } else {
+                // The normals are averaged over the pixel, so they need renormalising
+                let cos = dot(&unit_vector(&np), &unit_vector(&nq));
+                weight *= f32::max(0.0, cos).powi(SIGMA_NORMAL);
+            }
+        }
+        if let Some(depth) = &self.depth {
+            let (zp, zq) = (depth[p].r(), depth[q].r());
+            let scale = SIGMA_DEPTH * f32::max(zp, zq) * distance;
+            if scale > 0.0 {
+                weight *= f32::exp(-(zp - zq).abs() / scale);
+            }
+        }
+        weight
+    }
+}
+
+/// Return a denoised version of the film's image. Higher values of `strength` allow larger
+/// luminance differences to be smoothed out, and 0 leaves the image untouched.
+///
+/// The albedo AOV, if present, is divided out before filtering and multiplied back afterwards, so
+/// that texture detail isn't blurred. The normal and depth AOVs, if present, preserve geometric
+/// edges.
+pub fn denoise(film: &Film, strength: f32) -> Vec<Vec3> {
+    let width = film.width();
+    let height = film.height();
+    let pixels = film.pixels();
+    let mut variances = film.variances();
+    if strength <= 0.0 {
+        return pixels;
+    }
+
+    // Emitters and black surfaces have no albedo to speak of, and are filtered as they are
+    let albedo = film.aov_pixels(Aov::Albedo).map(|albedo| {
+        albedo
+            .iter()
+            .map(|a| {
+                if a.max_component() < MIN_ALBEDO {
+                    Vec3::new(1.0, 1.0, 1.0)
+                } else {
+                    Vec3::new(
+                        f32::max(a.r(), MIN_ALBEDO),
+                        f32::max(a.g(), MIN_ALBEDO),
+                        f32::max(a.b(), MIN_ALBEDO),
+                    )
+                }
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut illumination = pixels;
+    if let Some(albedo) = &albedo {
+        for ((c, v), a) in illumination.iter_mut().zip(&mut variances).zip(albedo) {
+            *c = Vec3::new(c.r() / a.r(), c.g() / a.g(), c.b() / a.b());
+            let luminance = a.luminance();
+            *v /= luminance * luminance;
+        }
+    }
+
+    let guides = Guides {
+        width,
+        height,
+        normal: film.aov_pixels(Aov::Normal),
+        depth: film.aov_pixels(Aov::Depth),
+    };
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let (c, v) = filter_pass(&illumination, &variances, &guides, step, strength);
+        illumination = c;
+        variances = v;
+    }
+
+    if let Some(albedo) = &albedo {
+        for (c, a) in illumination.iter_mut().zip(albedo) {
+            *c = *c * *a;
+        }
+    }
+    illumination
+}
+
+/// Apply one pass of the à-trous filter, with taps `step` pixels apart. Returns the filtered
+/// colours and their variances.
+fn filter_pass(
+    colors: &[Vec3],
+    variances: &[f32],
+    guides: &Guides,
+    step: usize,
+    strength: f32,
+) -> (Vec<Vec3>, Vec<f32>) {
+    let (width, height) = (guides.width, guides.height);
+    let blurred_variances = blur_3x3(variances, width, height);
+    let rows: Vec<(Vec<Vec3>, Vec<f32>)> = (0..height)
+        .into_par_iter()
+        .map(|y| {
+            let mut row_colors = Vec::with_capacity(width);
+            let mut row_variances = Vec::with_capacity(width);
+            for x in 0..width {
+                let p = y * width + x;
+                let luminance_p = colors[p].luminance();
+                let sigma = SIGMA_LUMINANCE * strength * f32::sqrt(blurred_variances[p]) + 1e-4;
+                let mut sum_weights = 0.0;
+                let mut sum_colors = Vec3::default();
+                let mut sum_variances = 0.0;
+                for (ky, hy) in KERNEL.iter().enumerate() {
+                    let dy = (ky as isize - 2) * step as isize;
+                    let qy = y as isize + dy;
+                    if qy < 0 || qy >= height as isize {
+                        continue;
+                    }
+                    for (kx, hx) in KERNEL.iter().enumerate() {
+                        let dx = (kx as isize - 2) * step as isize;
+                        let qx = x as isize + dx;
+                        if qx < 0 || qx >= width as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+                        let distance = f32::sqrt((dx * dx + dy * dy) as f32);
+                        let luminance_weight =
+                            f32::exp(-(luminance_p - colors[q].luminance()).abs() / sigma);
+                        let weight = hx * hy * luminance_weight * guides.weight(p, q, distance);
+                        sum_weights += weight;
+                        sum_colors += weight * colors[q];
+                        sum_variances += weight * weight * variances[q];
+                    }
+                }
+                // The centre tap always has a weight of at least 9/64
+                row_colors.push(sum_colors / sum_weights);
+                row_variances.push(sum_variances / (sum_weights * sum_weights));
+            }
+            (row_colors, row_variances)
+        })
+        .collect();
+
+    let mut out_colors = Vec::with_capacity(width * height);
+    let mut out_variances = Vec::with_capacity(width * height);
+    for (row_colors, row_variances) in rows {
+        out_colors.extend(row_colors);
+        out_variances.extend(row_variances);
+    }
+    (out_colors, out_variances)
+}
+
+/// Blur the variances with a 3x3 gaussian, which makes the luminance weights more robust.
+fn blur_3x3(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    const WEIGHTS: [f32; 3] = [0.25, 0.5, 0.25];
+    let mut out = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            let mut sum_weights = 0.0;
+            for (ky, wy) in WEIGHTS.iter().enumerate() {
+                for (kx, wx) in WEIGHTS.iter().enumerate() {
+                    let qx = x as isize + kx as isize - 1;
+                    let qy = y as isize + ky as isize - 1;
+                    if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                        continue;
+                    }
+                    sum += wx * wy * values[qy as usize * width + qx as usize];
+                    sum_weights += wx * wy;
+                }
+            }
+            out[y * width + x] = sum / sum_weights;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::filter::FilterKind;
+    use crate::random::seeded_rng;
+    use crate::render::Tile;
+
+    const SIZE: usize = 16;
+
+    /// Return a noisy film of a grey wall on the left, facing right, and a slightly lighter one on
+    /// the right, facing up. Their noise is larger than the difference between them.
+    fn noisy_walls() -> Film {
+        let mut film = Film::new(SIZE, SIZE, &[Aov::Normal]);
+        let filter = FilterKind::Box.create(0.5);
+        let tile = Tile {
+            x0: 0,
+            y0: 0,
+            x1: SIZE,
+            y1: SIZE,
+        };
+        let mut film_tile = film.tile(&tile, filter.radius());
+        let mut rng = seeded_rng(3);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let (grey, normal) = if x < SIZE / 2 {
+                    (0.4, Vec3::new(1.0, 0.0, 0.0))
+                } else {
+                    (0.6, Vec3::new(0.0, 1.0, 0.0))
+                };
+                for _ in 0..4 {
+                    let v = grey * rng.gen_range(0.5..1.5);
+                    let l = Vec3::new(v, v, v);
+                    let (u, w) = (x as f32 + 0.5, y as f32 + 0.5);
+                    film_tile.add_sample(u, w, &l, &*filter);
+                    film_tile.add_pixel_sample(x, y, &l, &[normal]);
+                }
+            }
+        }
+        film.add_tile(&film_tile);
+        film
+    }
+
+    /// Return the mean and variance of the luminance of the left or right half of the image.
+    fn statistics(pixels: &[Vec3], right: bool) -> (f32, f32) {
+        let half: Vec<f32> = pixels
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (i % SIZE >= SIZE / 2) == right)
+            .map(|(_, p)| p.luminance())
+            .collect();
+        let n = half.len() as f32;
+        let mean = half.iter().sum::<f32>() / n;
+        let variance = half.iter().map(|l| (l - mean) * (l - mean)).sum::<f32>() / n;
+        (mean, variance)
+    }
+
+    #[test]
+    fn zero_strength_leaves_image_untouched() {
+        let film = noisy_walls();
+        assert_eq!(denoise(&film, 0.0), film.pixels());
+    }
+
+    #[test]
+    fn noise_is_reduced_and_edges_are_kept() {
+        let film = noisy_walls();
+        let noisy = film.pixels();
+        let denoised = denoise(&film, 1.0);
+        for right in [false, true] {
+            let (noisy_mean, noisy_variance) = statistics(&noisy, right);
+            let (mean, variance) = statistics(&denoised, right);
+            assert!(
+                variance < 0.25 * noisy_variance,
+                "{} {}",
+                variance,
+                noisy_variance
+            );
+            assert!((mean - noisy_mean).abs() < 0.05 * noisy_mean);
+        }
+        // The walls don't bleed into each other
+        for y in 0..SIZE {
+            let left = denoised[y * SIZE + SIZE / 2 - 1].luminance();
+            let right = denoised[y * SIZE + SIZE / 2].luminance();
+            assert!(left < 0.45 && right > 0.55, "{} {}", left, right);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Density fields describing how much matter there is at each point of a heterogeneous medium.
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use log::info;
+
+use crate::perlin;
+use crate::vec::Vec3;
+
+/// Largest number of voxels in a grid read from a file, 512 MiB of densities. Larger sizes are
+/// assumed to come from a corrupt file.
+const MAX_GRID_SIZE: usize = 1 << 27;
+
+pub trait Density: Debug + Send + Sync {
+    /// Return the density at point `p`.
+    fn value(&self, p: &Vec3) -> f32;
+
+    /// Return an upper bound of the density over the whole space, used as the majorant when
+    /// tracking rays through the medium. The tighter it is, the fewer steps are needed.
+    fn max_value(&self) -> f32;
+}
+
+/// Density following perlin turbulence, for procedural clouds and smoke.
+#[derive(Debug, Clone)]
+pub struct NoiseDensity {
+    /// Frequency of the noise
+    scale: f32,
+    /// Density where the turbulence is 1
+    density: f32,
+    /// Number of octaves of noise
+    depth: u32,
+}
+
+impl NoiseDensity {
+    pub fn new(scale: f32, density: f32) -> NoiseDensity {
+        NoiseDensity {
+            scale,
+            density,
+            depth: 7,
+        }
+    }
+}
+
+impl Density for NoiseDensity {
+    fn value(&self, p: &Vec3) -> f32 {
+        self.density * perlin::turb(&(self.scale * p), self.depth)
+    }
+
+    fn max_value(&self) -> f32 {
+        // Each octave of noise interpolates dot products of unit vectors with offsets of length at
+        // most sqrt(3), and the octaves' weights sum to less than 2
+        self.density * 2.0 * f32::sqrt(3.0)
+    }
+}
+
+/// Density sampled on a regular grid of voxels spanning the box between `pmin` and `pmax`, and
+/// trilinearly interpolated between voxel centres. The density is zero outside of the box.
+#[derive(Debug, Clone)]
+pub struct GridDensity {
+    pmin: Vec3,
+    pmax: Vec3,
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    /// Densities of the voxels, x varying fastest, then y, then z
+    data: Box<[f32]>,
+    max_value: f32,
+}
+
+impl GridDensity {
+    pub fn new(
+        pmin: Vec3,
+        pmax: Vec3,
+        nx: usize,
+        ny: usize,
+        nz: usize,
+        data: Vec<f32>,
+    ) -> GridDensity {
+        assert_eq!(
+            data.len(),
+            nx * ny * nz,
+            "grid data doesn't match its dimensions"
+        );
+        let max_value = data.iter().cloned().fold(0.0, f32::max);
+
+        GridDensity {
+            pmin,
+            pmax,
+            nx,
+            ny,
+            nz,
+            data: data.into_boxed_slice(),
+            max_value,
+        }
+    }
+
+    /// Load a grid from a raw file: its dimensions `nx`, `ny` and `nz` as little-endian `u32`,
+    /// followed by the `nx * ny * nz` densities as little-endian `f32`, x varying fastest, then y,
+    /// then z.
+    pub fn open<P: AsRef<Path>>(filename: P, pmin: Vec3, pmax: Vec3) -> io::Result<GridDensity> {
+        let filename = filename.as_ref();
+        let (nx, ny, nz, data) = read_grid(filename)?;
+        info!(
+            "Loaded density grid {} with size {}x{}x{}",
+            filename.display(),
+            nx,
+            ny,
+            nz
+        );
+
+        Ok(GridDensity::new(pmin, pmax, nx, ny, nz, data))
+    }
+
+    fn voxel(&self, i: usize, j: usize, k: usize) -> f32 {
+        self.data[i + self.nx * (j + self.ny * k)]
+    }
+}
+
+fn read_grid(filename: &Path) -> io::Result<(usize, usize, usize, Vec<f32>)> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    let mut word = [0u8; 4];
+    let mut dims = [0usize; 3];
+    for dim in dims.iter_mut() {
+        reader.read_exact(&mut word)?;
+        *dim = u32::from_le_bytes(word) as usize;
+    }
+    let [nx, ny, nz] = dims;
+    if nx == 0 || ny == 0 || nz == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty grid"));
+    }
+
+    let size = nx
+        .checked_mul(ny)
+        .and_then(|size| size.checked_mul(nz))
+        .filter(|&size| size <= MAX_GRID_SIZE)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("grid of {}x{}x{} voxels is too large", nx, ny, nz),
+            )
+        })?;
+    let mut data = Vec::with_capacity(size);
+    for _ in 0..size {
+        reader.read_exact(&mut word)?;
+        data.push(f32::from_le_bytes(word));
+    }
+
+    Ok((nx, ny, nz, data))
+}
+
+impl Density for GridDensity {
+    fn value(&self, p: &Vec3) -> f32 {
+        let dims = [self.nx, self.ny, self.nz];
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for axis in 0..3 {
+            let extent = self.pmax[axis] - self.pmin[axis];
+            let t = (p[axis] - self.pmin[axis]) / extent;
+            if !(0.0..=1.0).contains(&t) {
+                return 0.0;
+            }
+            // Position relative to the voxel centres
+            let x = (t * dims[axis] as f32 - 0.5).clamp(0.0, (dims[axis] - 1) as f32);
+            base[axis] = (x as usize).min(dims[axis].saturating_sub(2));
+            frac[axis] = if dims[axis] > 1 {
+                x - base[axis] as f32
+            } else {
+                0.0
+            };
+        }
+
+        let mut value = 0.0;
+        for dk in 0..2 {
+            for dj in 0..2 {
+                for di in 0..2 {
+                    let weight = (if di == 1 { frac[0] } else { 1.0 - frac[0] })
+                        * (if dj == 1 { frac[1] } else { 1.0 - frac[1] })
+                        * (if dk == 1 { frac[2] } else { 1.0 - frac[2] });
+                    if weight > 0.0 {
+                        value += weight * self.voxel(base[0] + di, base[1] + dj, base[2] + dk);
+                    }
+                }
+            }
+        }
+
+        value
+    }
+
+    fn max_value(&self) -> f32 {
+        self.max_value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Write a grid file with the given header and densities, and return its path.
+    fn grid_file(name: &str, dims: [u32; 3], data: &[f32]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rtiow-{}-{}.raw", std::process::id(), name));
+        let mut bytes = Vec::new();
+        for dim in dims {
+            bytes.extend(dim.to_le_bytes());
+        }
+        for value in data {
+            bytes.extend(value.to_le_bytes());
+        }
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn read_error(name: &str, dims: [u32; 3], data: &[f32]) -> io::ErrorKind {
+        let path = grid_file(name, dims, data);
+        let result = read_grid(&path);
+        fs::remove_file(path).unwrap();
+        result.unwrap_err().kind()
+    }
+
+    #[test]
+    fn reads_grid() {
+        let data = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let path = grid_file("grid", [3, 2, 1], &data);
+        let (nx, ny, nz, read) = read_grid(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!((nx, ny, nz), (3, 2, 1));
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn rejects_invalid_grids() {
+        assert_eq!(
+            read_error("empty", [0, 2, 2], &[]),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read_error("truncated", [2, 2, 2], &[1.0; 7]),
+            io::ErrorKind::UnexpectedEof
+        );
+        let large = [1 << 14, 1 << 14, 1 << 14];
+        assert_eq!(
+            read_error("large", large, &[1.0]),
+            io::ErrorKind::InvalidData
+        );
+        // The number of voxels overflows
+        let huge = [u32::MAX, u32::MAX, 16];
+        assert_eq!(read_error("huge", huge, &[1.0]), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn interpolates_between_voxel_centres() {
+        let pmax = Vec3::new(2.0, 1.0, 1.0);
+        let grid = GridDensity::new(Vec3::default(), pmax, 2, 1, 1, vec![1.0, 3.0]);
+        assert_eq!(grid.max_value(), 3.0);
+        assert_eq!(grid.value(&Vec3::new(0.5, 0.5, 0.5)), 1.0);
+        assert_eq!(grid.value(&Vec3::new(1.0, 0.5, 0.5)), 2.0);
+        assert_eq!(grid.value(&Vec3::new(1.5, 0.5, 0.5)), 3.0);
+        assert_eq!(grid.value(&Vec3::new(0.1, 0.5, 0.5)), 1.0);
+        assert_eq!(grid.value(&Vec3::new(2.5, 0.5, 0.5)), 0.0);
+    }
+}
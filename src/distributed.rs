@@ -0,0 +1,428 @@
+//! Distributed rendering. A coordinator hands out the tiles of each pass to worker processes, on
+//! this machine or others, which send back the film tiles they rendered. Workers run the same
+//! program with the same settings, so they build the same scene, and the coordinator merges the
+//! tiles in a fixed order, so the image is the same as if a single process had rendered it.
+//!
+//! Messages are exchanged over TCP, with all numbers in little-endian order.
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use rayon::prelude::*;
+
+use crate::film::{read_u32, write_u32, FilmTile};
+use crate::render::{Renderer, Tile, TileJob};
+
+/// Sent by workers when they connect, followed by the version of the protocol.
+const MAGIC: &[u8; 8] = b"RTIOWDST";
+const VERSION: u32 = 2;
+
+/// Messages sent by the coordinator to its workers.
+const SHUTDOWN: u8 = 0;
+const JOBS: u8 = 1;
+
+/// Number of jobs sent to a worker at once for each of its threads, so that they don't sit idle
+/// while the results travel back.
+const JOBS_PER_THREAD: usize = 2;
+
+/// Time after which a worker which doesn't send back the tiles of its jobs, or doesn't read what
+/// it is sent, is given up on. It is generous, as a batch of tiles with many samples can take
+/// minutes to render.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Time after which a worker waiting for jobs gives up on the coordinator. At the end of each
+/// pass, workers wait for the slowest of them to finish its batch.
+const COORDINATOR_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// A worker connected to the coordinator.
+struct Worker {
+    /// Address of the worker, for messages
+    name: String,
+    /// Number of tiles the worker renders in parallel
+    threads: usize,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Worker {
+    /// Have the worker render some jobs for `renderer`, and return the film tiles it sends back.
+    /// Tiles which don't match their job are an error.
+    fn render(&mut self, jobs: &[&TileJob], renderer: &Renderer) -> io::Result<Vec<FilmTile>> {
+        self.writer.write_all(&[JOBS])?;
+        write_u32(&mut self.writer, jobs.len() as u32)?;
+        for job in jobs {
+            write_job(&mut self.writer, job)?;
+        }
+        self.writer.flush()?;
+
+        jobs.iter()
+            .map(|job| {
+                let bounds = renderer.film_tile_bounds(&job.tile);
+                FilmTile::read_state(&mut self.reader, &bounds, renderer.aovs())
+            })
+            .collect()
+    }
+}
+
+/// The process handing out tiles to workers.
+pub struct Coordinator {
+    workers: Vec<Worker>,
+    /// Worker processes started by the coordinator
+    children: Vec<Child>,
+}
+
+impl Coordinator {
+    /// Wait for `count` workers to connect to `listener`. Workers whose settings differ from those
+    /// of `renderer` are turned away.
+    pub fn accept(
+        listener: &TcpListener,
+        count: usize,
+        renderer: &Renderer,
+    ) -> io::Result<Coordinator> {
+        let mut coordinator = Coordinator {
+            workers: Vec::new(),
+            children: Vec::new(),
+        };
+        coordinator.accept_workers(listener, count, renderer)?;
+        Ok(coordinator)
+    }
+
+    /// Start `count` worker processes on this machine, and wait for them to connect. The workers
+    /// run this program with the arguments `--worker <address>`, followed by `args`.
+    pub fn spawn(count: usize, args: &[&str], renderer: &Renderer) -> io::Result<Coordinator> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        let program = std::env::current_exe()?;
+        let mut coordinator = Coordinator {
+            workers: Vec::new(),
+            children: Vec::new(),
+        };
+        for _ in 0..count {
+            let child = Command::new(&program)
+                .arg("--worker")
+                .arg(&address)
+                .args(args)
+                .spawn()?;
+            coordinator.children.push(child);
+        }
+        coordinator.accept_workers(&listener, count, renderer)?;
+        Ok(coordinator)
+    }
+
+    /// Number of workers still connected.
+    pub fn worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    fn accept_workers(
+        &mut self,
+        listener: &TcpListener,
+        count: usize,
+        renderer: &Renderer,
+    ) -> io::Result<()> {
+        // Poll, so that workers started by the coordinator which die before connecting are noticed
+        listener.set_nonblocking(true)?;
+        info!(
+            "Waiting for {} workers on {}...",
+            count,
+            listener.local_addr()?
+        );
+        while self.workers.len() < count {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    stream.set_nonblocking(false)?;
+                    match handshake(stream, renderer.fingerprint()) {
+                        Ok(worker) => {
+                            info!("Worker {} connected ({} threads)", address, worker.threads);
+                            self.workers.push(worker);
+                        }
+                        Err(e) => warn!("Rejected worker {}: {}", address, e),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    for child in &mut self.children {
+                        if let Some(status) = child.try_wait()? {
+                            return Err(io::Error::other(format!(
+                                "worker process exited with {}",
+                                status
+                            )));
+                        }
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Render the given jobs of `renderer` on the workers, and return the film tiles in the order
+    /// of the jobs. Jobs are handed out in batches as workers become free. The jobs of a worker
+    /// which fails are handed to the others, and are left as `None` if none of them is free to
+    /// take them.
+    pub fn render(&mut self, jobs: &[TileJob], renderer: &Renderer) -> Vec<Option<FilmTile>> {
+        let queue = Mutex::new((0..jobs.len()).collect::<VecDeque<_>>());
+        let results = Mutex::new(vec![None; jobs.len()]);
+        let failed = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for (index, worker) in self.workers.iter_mut().enumerate() {
+                let (queue, results, failed) = (&queue, &results, &failed);
+                scope.spawn(move || loop {
+                    let batch: Vec<usize> = {
+                        let mut queue = queue.lock().unwrap();
+                        let size = usize::min(worker.threads * JOBS_PER_THREAD, queue.len());
+                        queue.drain(..size).collect()
+                    };
+                    if batch.is_empty() {
+                        break;
+                    }
+
+                    let batch_jobs: Vec<&TileJob> = batch.iter().map(|&i| &jobs[i]).collect();
+                    match worker.render(&batch_jobs, renderer) {
+                        Ok(film_tiles) => {
+                            let mut results = results.lock().unwrap();
+                            for (i, film_tile) in batch.into_iter().zip(film_tiles) {
+                                results[i] = Some(film_tile);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Worker {} failed: {}", worker.name, e);
+                            queue.lock().unwrap().extend(batch);
+                            failed.lock().unwrap().push(index);
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let failed = failed.into_inner().unwrap();
+        let mut index = 0;
+        self.workers.retain(|_| {
+            index += 1;
+            !failed.contains(&(index - 1))
+        });
+        results.into_inner().unwrap()
+    }
+}
+
+impl Drop for Coordinator {
+    /// Tell the workers to stop, and wait for the processes started by the coordinator.
+    fn drop(&mut self) {
+        for worker in &mut self.workers {
+            let _ = worker
+                .writer
+                .write_all(&[SHUTDOWN])
+                .and_then(|_| worker.writer.flush());
+        }
+        for child in &mut self.children {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Check the greeting of a newly connected worker, and accept it if it renders the same image as
+/// the coordinator, whose fingerprint is given.
+fn handshake(stream: TcpStream, fingerprint: u64) -> io::Result<Worker> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(WORKER_TIMEOUT))?;
+    stream.set_write_timeout(Some(WORKER_TIMEOUT))?;
+    let name = stream.peer_addr()?.to_string();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a worker, or from an incompatible version",
+        ));
+    }
+    let mut worker_fingerprint = [0u8; 8];
+    reader.read_exact(&mut worker_fingerprint)?;
+    let threads = read_u32(&mut reader)?.max(1) as usize;
+
+    let accepted = u64::from_le_bytes(worker_fingerprint) == fingerprint;
+    writer.write_all(&[accepted as u8])?;
+    writer.flush()?;
+    if !accepted {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the worker's settings differ",
+        ));
+    }
+
+    Ok(Worker {
+        name,
+        threads,
+        reader,
+        writer,
+    })
+}
+
+/// Connect to the coordinator at `address`, and render the tiles it sends until it tells the
+/// worker to stop. Tiles are rendered in parallel on the thread pool.
+pub fn run_worker<A: ToSocketAddrs>(address: A, renderer: &Renderer) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(COORDINATOR_TIMEOUT))?;
+    stream.set_write_timeout(Some(WORKER_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    writer.write_all(MAGIC)?;
+    write_u32(&mut writer, VERSION)?;
+    writer.write_all(&renderer.fingerprint().to_le_bytes())?;
+    write_u32(&mut writer, rayon::current_num_threads() as u32)?;
+    writer.flush()?;
+    if read_u8(&mut reader)? == 0 {
+        return Err(io::Error::other(
+            "rejected by the coordinator, whose settings differ",
+        ));
+    }
+    info!("Connected to the coordinator");
+
+    loop {
+        match read_u8(&mut reader)? {
+            SHUTDOWN => return Ok(()),
+            JOBS => {
+                let count = read_u32(&mut reader)?;
+                let jobs = (0..count)
+                    .map(|_| read_job(&mut reader, renderer))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let film_tiles: Vec<FilmTile> = jobs
+                    .par_iter()
+                    .map(|job| renderer.render_job(job))
+                    .collect();
+                for film_tile in &film_tiles {
+                    film_tile.write_state(&mut writer)?;
+                }
+                writer.flush()?;
+            }
+            message => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message {}", message),
+                ))
+            }
+        }
+    }
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Write a job as the bounds of its tile, followed by the range of samples of each pixel.
+fn write_job<W: Write>(out: &mut W, job: &TileJob) -> io::Result<()> {
+    let tile = &job.tile;
+    for value in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        write_u32(out, value as u32)?;
+    }
+    for range in &job.samples {
+        write_u32(out, range.start as u32)?;
+        write_u32(out, range.end as u32)?;
+    }
+    Ok(())
+}
+
+/// Read a job written by `write_job`. Tiles which aren't within the film of `renderer` are
+/// rejected as invalid.
+fn read_job<R: Read>(input: &mut R, renderer: &Renderer) -> io::Result<TileJob> {
+    let mut bounds = [0; 4];
+    for value in &mut bounds {
+        *value = read_u32(input)? as usize;
+    }
+    let [x0, y0, x1, y1] = bounds;
+    let tile = Tile { x0, y0, x1, y1 };
+    // Film tiles are clamped to the film, so they only hold tiles which are within it
+    let film_bounds = renderer.film_tile_bounds(&tile);
+    if x1 < x0 || y1 < y0 || x1 > film_bounds.x1 || y1 > film_bounds.y1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid tile {:?}", tile),
+        ));
+    }
+    let samples = (0..tile.width() * tile.height())
+        .map(|_| Ok(read_u32(input)? as usize..read_u32(input)? as usize))
+        .collect::<io::Result<_>>()?;
+
+    Ok(TileJob { tile, samples })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use super::*;
+    use crate::render::tests::{test_scene, test_settings};
+    use crate::render::{render, render_distributed};
+
+    #[test]
+    fn distributed_render_matches_local_render() {
+        let settings = test_settings();
+        let (scene, camera) = test_scene(&settings);
+        let renderer = Renderer::new(&scene, &camera, &settings);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let film = thread::scope(|scope| {
+            let workers: Vec<_> = (0..2)
+                .map(|_| scope.spawn(|| run_worker(address, &renderer)))
+                .collect();
+            let mut coordinator = Coordinator::accept(&listener, 2, &renderer).unwrap();
+            let film = render_distributed(&renderer, None, &mut coordinator, |_, _| {
+                ControlFlow::Continue(())
+            });
+            assert_eq!(coordinator.worker_count(), 2);
+            drop(coordinator);
+            for worker in workers {
+                worker.join().unwrap().unwrap();
+            }
+            film
+        });
+
+        let local = render(&scene, &camera, &settings);
+        assert_eq!(film.pixels(), local.pixels());
+        for &aov in &settings.aovs {
+            assert_eq!(film.aov_pixels(aov), local.aov_pixels(aov));
+        }
+    }
+
+    #[test]
+    fn jobs_outside_the_film_are_rejected() {
+        let settings = test_settings();
+        let (scene, camera) = test_scene(&settings);
+        let renderer = Renderer::new(&scene, &camera, &settings);
+        // Invalid tiles are rejected before their samples are read
+        let read = |tile: Tile, samples: usize| {
+            let job = TileJob {
+                tile,
+                samples: vec![0..1; samples],
+            };
+            let mut bytes = Vec::new();
+            write_job(&mut bytes, &job).unwrap();
+            read_job(&mut bytes.as_slice(), &renderer)
+        };
+
+        let tile = Tile {
+            x0: 16,
+            y0: 8,
+            x1: 24,
+            y1: 16,
+        };
+        assert_eq!(read(tile, 64).unwrap().tile, tile);
+        for (x1, y1) in [(25, 16), (24, 17), (u32::MAX as usize, 16), (8, 16)] {
+            let error = read(Tile { x1, y1, ..tile }, 0).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
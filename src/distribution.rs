@@ -0,0 +1,178 @@
+//! Piecewise-constant distributions, used to importance sample tabulated functions such as
+//! environment maps.
+
+/// Distribution over [0, 1) proportional to a step function with equally sized steps.
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    func_int: f32,
+}
+
+impl Distribution1D {
+    /// Create the distribution of the given step function, whose values must not be negative.
+    pub fn new(f: &[f32]) -> Distribution1D {
+        let n = f.len();
+        let func = f.to_vec();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f32;
+        }
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            // Nothing to importance sample, fall back to a uniform distribution
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            func_int,
+        }
+    }
+
+    /// Number of steps of the function.
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Integral of the function over [0, 1).
+    pub fn func_int(&self) -> f32 {
+        self.func_int
+    }
+
+    /// Map the uniform sample `u` to a point of the distribution. Returns the point, its density
+    /// and the index of the step it falls in.
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        // Last index whose cdf is less than or equal to u
+        let offset = self
+            .cdf
+            .partition_point(|&c| c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let x = (offset as f32 + du) / self.count() as f32;
+        (x.min(1.0), self.pdf(offset), offset)
+    }
+
+    /// Density of the points in step `offset`.
+    pub fn pdf(&self, offset: usize) -> f32 {
+        if self.func_int > 0.0 {
+            self.func[offset] / self.func_int
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Distribution over [0, 1)² proportional to a function tabulated on a regular grid. A row is first
+/// picked following the marginal distribution, then a column within that row.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// Create the distribution of the function whose `nu * nv` values are given row by row.
+    pub fn new(f: &[f32], nu: usize, nv: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> =
+            f.chunks(nu).take(nv).map(Distribution1D::new).collect();
+        let marginal_func: Vec<f32> = conditional.iter().map(|d| d.func_int()).collect();
+
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal_func),
+        }
+    }
+
+    /// Map the uniform sample `(u0, u1)` to a point `(u, v)` of the distribution, and return it
+    /// with its density.
+    pub fn sample_continuous(&self, (u0, u1): (f32, f32)) -> ((f32, f32), f32) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u0);
+
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    /// Density of the point `(u, v)`.
+    pub fn pdf(&self, (u, v): (f32, f32)) -> f32 {
+        let nu = self.conditional[0].count();
+        let nv = self.marginal.count();
+        let iu = ((u * nu as f32) as usize).min(nu - 1);
+        let iv = ((v * nv as f32) as usize).min(nv - 1);
+
+        self.conditional[iv].pdf(iu) * self.marginal.pdf(iv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x3 function with an empty row and an empty cell.
+    const NU: usize = 4;
+    const NV: usize = 3;
+    const F: [f32; NU * NV] = [1.0, 2.0, 0.0, 5.0, 0.0, 0.0, 0.0, 0.0, 3.0, 3.0, 1.0, 1.0];
+
+    /// Return a grid of `n * n` uniform samples, at the centres of the cells.
+    fn uniform_grid(n: usize) -> impl Iterator<Item = (f32, f32)> {
+        (0..n * n).map(move |i| {
+            let u = ((i % n) as f32 + 0.5) / n as f32;
+            let v = ((i / n) as f32 + 0.5) / n as f32;
+            (u, v)
+        })
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let distribution = Distribution2D::new(&F, NU, NV);
+        // Each cell holds as many points of the grid
+        let integral: f32 = uniform_grid(120)
+            .map(|uv| distribution.pdf(uv) / (120 * 120) as f32)
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-4, "{}", integral);
+    }
+
+    #[test]
+    fn samples_follow_the_function() {
+        let distribution = Distribution2D::new(&F, NU, NV);
+        let n = 200;
+        let mut counts = [0usize; NU * NV];
+        for sample in uniform_grid(n) {
+            let ((u, v), pdf) = distribution.sample_continuous(sample);
+            assert!((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v));
+            assert!((pdf - distribution.pdf((u, v))).abs() < 1e-4 * pdf);
+            let cell = ((v * NV as f32) as usize).min(NV - 1) * NU
+                + ((u * NU as f32) as usize).min(NU - 1);
+            counts[cell] += 1;
+        }
+
+        let total: f32 = F.iter().sum();
+        for (count, f) in counts.iter().zip(F) {
+            let expected = f / total * (n * n) as f32;
+            assert!((*count as f32 - expected).abs() <= 0.01 * (n * n) as f32);
+            if f == 0.0 {
+                assert_eq!(*count, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_function_is_sampled_uniformly() {
+        let distribution = Distribution1D::new(&[0.0; 4]);
+        assert_eq!(distribution.sample_continuous(0.3), (0.3, 1.0, 1));
+        assert_eq!(distribution.pdf(3), 1.0);
+    }
+}
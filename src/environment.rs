@@ -0,0 +1,200 @@
+//! Light arriving from infinitely far away, seen by the rays which don't hit anything.
+use std::f32;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::ImageResult;
+
+use crate::distribution::Distribution2D;
+use crate::sampler::Sampler;
+use crate::texture::{HdrImageTexture, Texture};
+use crate::vec::{unit_vector, Vec3};
+
+/// Resolution of the distribution used to sample environment maps whose resolution is unknown.
+const DEFAULT_DISTRIBUTION_SIZE: (usize, usize) = (512, 256);
+
+pub trait Environment: Debug + Send + Sync {
+    /// Return the radiance arriving from the given direction.
+    fn le(&self, direction: &Vec3) -> Vec3;
+
+    /// Whether the integrators can sample directions towards this environment, like they do for
+    /// lights.
+    fn is_sampleable(&self) -> bool {
+        false
+    }
+
+    /// Return the density, with respect to solid angle, with which `random` generates
+    /// `direction`.
+    fn pdf_value(&self, _direction: &Vec3) -> f32 {
+        0.0
+    }
+
+    /// Generate a random direction towards the environment.
+    fn random(&self, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+}
+
+/// Environment of the same colour in every direction. A black one gives the behaviour of a scene
+/// without any environment.
+#[derive(Debug, Clone, Default)]
+pub struct ConstantEnvironment {
+    color: Vec3,
+}
+
+impl ConstantEnvironment {
+    pub fn new(color: Vec3) -> ConstantEnvironment {
+        ConstantEnvironment { color }
+    }
+}
+
+impl Environment for ConstantEnvironment {
+    fn le(&self, _direction: &Vec3) -> Vec3 {
+        self.color
+    }
+}
+
+/// Environment blending linearly between two colours, from straight down to straight up.
+#[derive(Debug, Clone)]
+pub struct GradientEnvironment {
+    bottom: Vec3,
+    top: Vec3,
+}
+
+impl GradientEnvironment {
+    pub fn new(bottom: Vec3, top: Vec3) -> GradientEnvironment {
+        GradientEnvironment { bottom, top }
+    }
+
+    /// The white to light blue sky of "Ray Tracing In One Weekend".
+    pub fn sky() -> GradientEnvironment {
+        GradientEnvironment::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.7, 1.0))
+    }
+}
+
+impl Environment for GradientEnvironment {
+    fn le(&self, direction: &Vec3) -> Vec3 {
+        let t = 0.5 * (unit_vector(direction).y() + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+}
+
+/// Environment looked up in a texture with an equirectangular (latitude-longitude) mapping: `u`
+/// goes around the vertical axis, and `v` goes from straight up (0) to straight down (1). The
+/// texture is also given the unit direction as its point.
+///
+/// Directions are importance sampled following the luminance of the texture, tabulated on a grid
+/// and weighted by `sin(theta)` to account for the compression of the mapping near the poles.
+#[derive(Debug, Clone)]
+pub struct MapEnvironment {
+    texture: Arc<dyn Texture>,
+    /// Rotation around the vertical axis, in radians
+    rotation: f32,
+    /// Factor applied to the values of the texture
+    intensity: f32,
+    distribution: Distribution2D,
+}
+
+impl MapEnvironment {
+    pub fn new(texture: Arc<dyn Texture>, rotation: f32, intensity: f32) -> MapEnvironment {
+        let (nu, nv) = DEFAULT_DISTRIBUTION_SIZE;
+        MapEnvironment::with_resolution(texture, nu, nv, rotation, intensity)
+    }
+
+    /// Create an environment whose sampling distribution has `nu * nv` cells, which should match
+    /// the resolution of the texture.
+    pub fn with_resolution(
+        texture: Arc<dyn Texture>,
+        nu: usize,
+        nv: usize,
+        rotation: f32,
+        intensity: f32,
+    ) -> MapEnvironment {
+        let mut luminance = Vec::with_capacity(nu * nv);
+        for j in 0..nv {
+            let v = (j as f32 + 0.5) / nv as f32;
+            let sin_theta = f32::sin(f32::consts::PI * v);
+            for i in 0..nu {
+                let u = (i as f32 + 0.5) / nu as f32;
+                let direction = direction_from_uv(u, v, rotation);
+                luminance.push(texture.value(u, v, &direction).luminance().max(0.0) * sin_theta);
+            }
+        }
+
+        MapEnvironment {
+            texture,
+            rotation,
+            intensity,
+            distribution: Distribution2D::new(&luminance, nu, nv),
+        }
+    }
+
+    /// Load an equirectangular environment map from an image, typically a `.hdr` or `.exr` file.
+    pub fn open<P: AsRef<Path>>(
+        filename: P,
+        rotation: f32,
+        intensity: f32,
+    ) -> ImageResult<MapEnvironment> {
+        let texture = HdrImageTexture::open(filename)?;
+        let (nu, nv) = (texture.nx as usize, texture.ny as usize);
+        Ok(MapEnvironment::with_resolution(
+            Arc::new(texture),
+            nu,
+            nv,
+            rotation,
+            intensity,
+        ))
+    }
+
+    /// Return the texture coordinates of the given unit direction.
+    fn uv(&self, direction: &Vec3) -> (f32, f32) {
+        let phi = f32::atan2(direction.z(), direction.x()) - self.rotation;
+        let theta = f32::acos(direction.y().clamp(-1.0, 1.0));
+        let u = (phi / (2.0 * f32::consts::PI)).rem_euclid(1.0);
+        let v = theta * f32::consts::FRAC_1_PI;
+
+        (u, v)
+    }
+}
+
+impl Environment for MapEnvironment {
+    fn le(&self, direction: &Vec3) -> Vec3 {
+        let direction = unit_vector(direction);
+        let (u, v) = self.uv(&direction);
+        self.intensity * self.texture.value(u, v, &direction)
+    }
+
+    fn is_sampleable(&self) -> bool {
+        true
+    }
+
+    fn pdf_value(&self, direction: &Vec3) -> f32 {
+        let (u, v) = self.uv(&unit_vector(direction));
+        let sin_theta = f32::sin(f32::consts::PI * v);
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        // The mapping from (u, v) to directions stretches areas by 2 * pi^2 * sin(theta)
+        self.distribution.pdf((u, v)) / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta)
+    }
+
+    fn random(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let ((u, v), _) = self.distribution.sample_continuous(sampler.get_2d());
+        direction_from_uv(u, v, self.rotation)
+    }
+}
+
+/// Return the unit direction corresponding to the texture coordinates of an equirectangular map
+/// rotated by `rotation` around the vertical axis. This is the inverse of `MapEnvironment::uv`.
+fn direction_from_uv(u: f32, v: f32, rotation: f32) -> Vec3 {
+    let phi = 2.0 * f32::consts::PI * u + rotation;
+    let theta = f32::consts::PI * v;
+
+    Vec3::new(
+        f32::sin(theta) * f32::cos(phi),
+        f32::cos(theta),
+        f32::sin(theta) * f32::sin(phi),
+    )
+}
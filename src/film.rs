@@ -0,0 +1,761 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use exr::prelude::{AnyChannel, AnyChannels, FlatSamples, Image, WritableImage};
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageError, ImageResult, Rgb, RgbImage};
+
+use crate::aov::Aov;
+use crate::denoise;
+use crate::filter::Filter;
+use crate::render::Tile;
+use crate::tonemap::OutputTransform;
+use crate::vec::Vec3;
+
+/// The image being rendered. Each pixel accumulates the radiance of the samples around it,
+/// weighted by the reconstruction filter, along with the sum of those weights. Pixels are stored
+/// row by row, starting from the top-left corner of the image.
+///
+/// The film can also hold AOV buffers, which aren't filtered: each pixel only receives the values
+/// of its own samples.
+#[derive(Debug, Clone)]
+pub struct Film {
+    width: usize,
+    height: usize,
+    sums: Vec<Vec3>,
+    weights: Vec<f32>,
+    /// Number of samples taken in each pixel
+    counts: Vec<u32>,
+    /// Running mean of the luminance of the samples taken in each pixel, and sum of the squared
+    /// differences from it (Welford's algorithm)
+    luminance_means: Vec<f32>,
+    luminance_m2s: Vec<f32>,
+    aovs: Vec<Aov>,
+    /// Sums of the values of each AOV, in the same order as `aovs`
+    aov_sums: Vec<Vec<Vec3>>,
+    /// The denoised image, if the film has been denoised since its last change
+    denoised: Option<Vec<Vec3>>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, aovs: &[Aov]) -> Film {
+        let n = width * height;
+        Film {
+            width,
+            height,
+            sums: vec![Vec3::default(); n],
+            weights: vec![0.0; n],
+            counts: vec![0; n],
+            luminance_means: vec![0.0; n],
+            luminance_m2s: vec![0.0; n],
+            aovs: aovs.to_vec(),
+            aov_sums: vec![vec![Vec3::default(); n]; aovs.len()],
+            denoised: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Return the radiance of the given pixel: the weighted average of the samples around it, or
+    /// the denoised value if the film has been denoised.
+    pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+        let index = y * self.width + x;
+        match &self.denoised {
+            Some(denoised) => denoised[index],
+            None => resolve_radiance(self.sums[index], self.weights[index]),
+        }
+    }
+
+    /// Return the radiance of all the pixels, row by row.
+    pub fn pixels(&self) -> Vec<Vec3> {
+        if let Some(denoised) = &self.denoised {
+            return denoised.clone();
+        }
+        self.sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(sum, weight)| resolve_radiance(*sum, *weight))
+            .collect()
+    }
+
+    /// Return the number of samples taken in the given pixel.
+    pub fn sample_count(&self, x: usize, y: usize) -> u32 {
+        self.counts[y * self.width + x]
+    }
+
+    /// Return the average number of samples taken per pixel.
+    pub fn mean_sample_count(&self) -> f32 {
+        let total: u64 = self.counts.iter().map(|&n| n as u64).sum();
+        total as f32 / self.counts.len().max(1) as f32
+    }
+
+    /// Return the mean luminance of the samples taken in the given pixel, ignoring the
+    /// reconstruction filter.
+    pub fn mean_luminance(&self, x: usize, y: usize) -> f32 {
+        self.luminance_means[y * self.width + x]
+    }
+
+    /// Return an estimate of the variance of the mean luminance of the given pixel. It is computed
+    /// from the spread of the pixel's own samples, so it is zero for pixels with fewer than two
+    /// samples.
+    pub fn variance(&self, x: usize, y: usize) -> f32 {
+        self.variance_at(y * self.width + x)
+    }
+
+    /// Return the variances of all the pixels, row by row.
+    pub fn variances(&self) -> Vec<f32> {
+        (0..self.counts.len())
+            .map(|i| self.variance_at(i))
+            .collect()
+    }
+
+    fn variance_at(&self, index: usize) -> f32 {
+        let n = self.counts[index] as f32;
+        if n < 2.0 {
+            return 0.0;
+        }
+        self.luminance_m2s[index] / (n - 1.0) / n
+    }
+
+    /// Replace the image by a denoised version of it, until more samples are added. The raw
+    /// samples are kept.
+    pub fn denoise(&mut self, strength: f32) {
+        self.denoised = None;
+        self.denoised = Some(denoise::denoise(self, strength));
+    }
+
+    /// The AOVs stored in this film.
+    pub fn aovs(&self) -> &[Aov] {
+        &self.aovs
+    }
+
+    /// Return the values of the given AOV for all the pixels, row by row, or `None` if it isn't
+    /// stored in this film.
+    pub fn aov_pixels(&self, aov: Aov) -> Option<Vec<Vec3>> {
+        let k = self.aovs.iter().position(|a| *a == aov)?;
+        if aov == Aov::SampleCount {
+            let counts = self.counts.iter().map(|&n| {
+                let n = n as f32;
+                Vec3::new(n, n, n)
+            });
+            return Some(counts.collect());
+        }
+        let pixels = self.aov_sums[k]
+            .iter()
+            .zip(&self.counts)
+            .map(|(sum, count)| {
+                if aov.is_averaged() {
+                    resolve(*sum, *count as f32)
+                } else {
+                    *sum
+                }
+            })
+            .collect();
+
+        Some(pixels)
+    }
+
+    /// Create an empty tile covering the pixels which the samples taken in `tile` can contribute
+    /// to with a filter of the given radius.
+    pub fn tile(&self, tile: &Tile, radius: f32) -> FilmTile {
+        FilmTile::padded(tile, radius, self.width, self.height, &self.aovs)
+    }
+
+    /// Add the samples splatted into a tile to the film.
+    pub fn add_tile(&mut self, tile: &FilmTile) {
+        let bounds = &tile.bounds;
+        for (row, y) in (bounds.y0..bounds.y1).enumerate() {
+            for (col, x) in (bounds.x0..bounds.x1).enumerate() {
+                let src = row * bounds.width() + col;
+                let dst = y * self.width + x;
+                self.sums[dst] += tile.sums[src];
+                self.weights[dst] += tile.weights[src];
+                // Combine the luminance statistics as in Chan et al.'s parallel algorithm
+                let (na, nb) = (self.counts[dst] as f32, tile.counts[src] as f32);
+                if nb > 0.0 {
+                    let n = na + nb;
+                    let delta = tile.luminance_means[src] - self.luminance_means[dst];
+                    self.luminance_means[dst] += delta * nb / n;
+                    self.luminance_m2s[dst] +=
+                        tile.luminance_m2s[src] + delta * delta * na * nb / n;
+                }
+                let aov_sums = self.aov_sums.iter_mut().zip(&tile.aov_sums);
+                for (aov, (film_sums, tile_sums)) in self.aovs.iter().zip(aov_sums) {
+                    if aov.is_averaged() {
+                        film_sums[dst] += tile_sums[src];
+                    } else if self.counts[dst] == 0 && tile.counts[src] > 0 {
+                        // Like within a tile, the pixel keeps the value of its first sample
+                        film_sums[dst] = tile_sums[src];
+                    }
+                }
+                self.counts[dst] += tile.counts[src];
+            }
+        }
+        self.denoised = None;
+    }
+
+    /// Return the part of the film covering `tile`.
+    pub fn crop(&self, tile: &Tile) -> Film {
+        let mut film = Film::new(tile.width(), tile.height(), &self.aovs);
+        let w = self.width;
+        cut(&self.sums, w, &mut film.sums, tile);
+        cut(&self.weights, w, &mut film.weights, tile);
+        cut(&self.counts, w, &mut film.counts, tile);
+        cut(&self.luminance_means, w, &mut film.luminance_means, tile);
+        cut(&self.luminance_m2s, w, &mut film.luminance_m2s, tile);
+        for (src, dst) in self.aov_sums.iter().zip(&mut film.aov_sums) {
+            cut(src, w, dst, tile);
+        }
+        if let Some(denoised) = &self.denoised {
+            let mut pixels = vec![Vec3::default(); tile.width() * tile.height()];
+            cut(denoised, w, &mut pixels, tile);
+            film.denoised = Some(pixels);
+        }
+
+        film
+    }
+
+    /// Return a film of the given size with this film's pixels placed at `(x0, y0)`, and no
+    /// samples anywhere else.
+    pub fn expand(&self, width: usize, height: usize, x0: usize, y0: usize) -> Film {
+        let mut film = Film::new(width, height, &self.aovs);
+        let (w, origin) = (self.width, (x0, y0));
+        place(&self.sums, w, &mut film.sums, width, origin);
+        place(&self.weights, w, &mut film.weights, width, origin);
+        place(&self.counts, w, &mut film.counts, width, origin);
+        place(
+            &self.luminance_means,
+            w,
+            &mut film.luminance_means,
+            width,
+            origin,
+        );
+        place(
+            &self.luminance_m2s,
+            w,
+            &mut film.luminance_m2s,
+            width,
+            origin,
+        );
+        for (src, dst) in self.aov_sums.iter().zip(&mut film.aov_sums) {
+            place(src, w, dst, width, origin);
+        }
+        if let Some(denoised) = &self.denoised {
+            let mut pixels = vec![Vec3::default(); width * height];
+            place(denoised, w, &mut pixels, width, origin);
+            film.denoised = Some(pixels);
+        }
+
+        film
+    }
+
+    /// Write the samples accumulated in the film, so that it can be restored by `read_state`.
+    /// The denoised image isn't saved.
+    pub fn write_state<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_u32(out, self.width as u32)?;
+        write_u32(out, self.height as u32)?;
+        write_u32(out, self.aovs.len() as u32)?;
+        for aov in &self.aovs {
+            let name = aov.name().as_bytes();
+            write_u32(out, name.len() as u32)?;
+            out.write_all(name)?;
+        }
+
+        write_vec3s(out, &self.sums)?;
+        write_f32s(out, &self.weights)?;
+        for &count in &self.counts {
+            write_u32(out, count)?;
+        }
+        write_f32s(out, &self.luminance_means)?;
+        write_f32s(out, &self.luminance_m2s)?;
+        for sums in &self.aov_sums {
+            write_vec3s(out, sums)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a film written by `write_state`. Films which are too large to be genuine are
+    /// rejected as invalid, rather than allocated.
+    pub fn read_state<R: Read>(input: &mut R) -> io::Result<Film> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let width = read_u32(input)? as usize;
+        let height = read_u32(input)? as usize;
+        let n = width
+            .checked_mul(height)
+            .filter(|&n| n <= MAX_FILM_SIZE)
+            .ok_or_else(|| invalid(format!("film of {}x{} pixels is too large", width, height)))?;
+        let aov_count = read_u32(input)? as usize;
+        if aov_count > Aov::ALL.len() {
+            return Err(invalid(format!("film with {} AOVs", aov_count)));
+        }
+        let mut aovs = Vec::new();
+        for _ in 0..aov_count {
+            let length = read_u32(input)? as usize;
+            if length > MAX_AOV_NAME {
+                return Err(invalid(format!("AOV name of {} bytes", length)));
+            }
+            let mut name = vec![0; length];
+            input.read_exact(&mut name)?;
+            let name = String::from_utf8_lossy(&name);
+            let aov =
+                Aov::from_name(&name).ok_or_else(|| invalid(format!("unknown AOV {}", name)))?;
+            aovs.push(aov);
+        }
+
+        let mut film = Film::new(width, height, &aovs);
+        film.sums = read_vec3s(input, n)?;
+        film.weights = read_f32s(input, n)?;
+        film.counts = (0..n).map(|_| read_u32(input)).collect::<io::Result<_>>()?;
+        film.luminance_means = read_f32s(input, n)?;
+        film.luminance_m2s = read_f32s(input, n)?;
+        for sums in &mut film.aov_sums {
+            *sums = read_vec3s(input, n)?;
+        }
+
+        Ok(film)
+    }
+
+    /// Convert the film to an 8-bit sRGB image using the given transform.
+    pub fn to_rgb8(&self, transform: &OutputTransform) -> RgbImage {
+        to_rgb8(self.width, self.height, &self.pixels(), |c| {
+            transform.apply(c)
+        })
+    }
+
+    /// Save the film to `path`, in the format given by its extension. Radiance HDR (`.hdr`),
+    /// OpenEXR (`.exr`) and PFM (`.pfm`) files keep the linear radiance, while any other format
+    /// supported by the `image` crate (PNG, JPEG, TIFF, binary PPM...) gets an 8-bit image,
+    /// produced by `transform`.
+    ///
+    /// AOVs are written as extra layers of OpenEXR files, and to separate images named after the
+    /// AOV for other formats, e.g. `out.normal.png` next to `out.png`.
+    pub fn save<P: AsRef<Path>>(&self, path: P, transform: &OutputTransform) -> ImageResult<()> {
+        let path = path.as_ref();
+        if extension(path).as_deref() == Some("exr") {
+            return self.write_exr(path);
+        }
+
+        save_pixels(path, self.width, self.height, &self.pixels(), |c| {
+            transform.apply(c)
+        })?;
+        for &aov in &self.aovs {
+            let pixels = self.aov_pixels(aov).unwrap();
+            let max = pixels.iter().map(|p| p.max_component()).fold(0.0, f32::max);
+            save_pixels(
+                &aov_path(path, aov),
+                self.width,
+                self.height,
+                &pixels,
+                |c| aov.ldr_color(c, max),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the film as an OpenEXR image, with the beauty pass in the `R`, `G` and `B` channels
+    /// and each AOV in channels prefixed by its name, e.g. `normal.X`.
+    fn write_exr(&self, path: &Path) -> ImageResult<()> {
+        let mut channels = Vec::new();
+        let pixels = self.pixels();
+        for (c, name) in ["R", "G", "B"].iter().enumerate() {
+            let samples = pixels.iter().map(|p| p[c]).collect();
+            channels.push(AnyChannel::new(*name, FlatSamples::F32(samples)));
+        }
+        for &aov in &self.aovs {
+            let pixels = self.aov_pixels(aov).unwrap();
+            for (c, name) in aov.channels().iter().enumerate() {
+                let samples = pixels.iter().map(|p| p[c]).collect();
+                let name = format!("{}.{}", aov.name(), name);
+                channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(samples)));
+            }
+        }
+
+        Image::from_channels(
+            (self.width, self.height),
+            AnyChannels::sort(channels.into()),
+        )
+        .write()
+        .to_file(path)
+        .map_err(|e| ImageError::IoError(io::Error::other(e.to_string())))
+    }
+}
+
+/// Largest number of pixels in a film read by `Film::read_state`, 64 megapixels. Larger sizes are
+/// assumed to come from a corrupt file.
+const MAX_FILM_SIZE: usize = 1 << 26;
+
+/// Longest AOV name in a film read by `Film::read_state`.
+const MAX_AOV_NAME: usize = 64;
+
+/// Sums of filter weights below which a pixel is left black.
+const MIN_WEIGHT: f32 = 1e-4;
+
+/// Divide a pixel's weighted sum of radiance by the sum of the weights. Filters with negative
+/// lobes can leave this sum close to zero, or negative, where few samples were taken around the
+/// pixel, e.g. at the edges of a crop window: such pixels are left black rather than blown up,
+/// and negative radiance is clamped.
+fn resolve_radiance(sum: Vec3, weight: f32) -> Vec3 {
+    if weight < MIN_WEIGHT {
+        return Vec3::default();
+    }
+    let l = sum / weight;
+    Vec3::new(l.r().max(0.0), l.g().max(0.0), l.b().max(0.0))
+}
+
+/// Divide a pixel's weighted sum of values by the sum of the weights.
+fn resolve(mut sum: Vec3, weight: f32) -> Vec3 {
+    if weight == 0.0 {
+        return Vec3::default();
+    }
+    sum /= weight;
+    sum
+}
+
+/// Copy the rows of `src`, an image `src_width` pixels wide, into `dst`, an image `dst_width`
+/// pixels wide, with the top-left corner of `src` at `origin`.
+fn place<T: Copy>(
+    src: &[T],
+    src_width: usize,
+    dst: &mut [T],
+    dst_width: usize,
+    origin: (usize, usize),
+) {
+    let (x0, y0) = origin;
+    for (row, src_row) in src.chunks(src_width.max(1)).enumerate() {
+        let start = (y0 + row) * dst_width + x0;
+        dst[start..start + src_width].copy_from_slice(src_row);
+    }
+}
+
+/// Copy the pixels of `src`, an image `src_width` pixels wide, covered by `tile` into `dst`.
+fn cut<T: Copy>(src: &[T], src_width: usize, dst: &mut [T], tile: &Tile) {
+    for (row, dst_row) in dst.chunks_mut(tile.width().max(1)).enumerate() {
+        let start = (tile.y0 + row) * src_width + tile.x0;
+        dst_row.copy_from_slice(&src[start..start + tile.width()]);
+    }
+}
+
+pub fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_f32s<W: Write>(out: &mut W, values: &[f32]) -> io::Result<()> {
+    for value in values {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_vec3s<W: Write>(out: &mut W, values: &[Vec3]) -> io::Result<()> {
+    for value in values {
+        write_f32s(out, &[value.r(), value.g(), value.b()])?;
+    }
+    Ok(())
+}
+
+pub fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut word = [0u8; 4];
+    input.read_exact(&mut word)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+fn read_f32s<R: Read>(input: &mut R, n: usize) -> io::Result<Vec<f32>> {
+    (0..n)
+        .map(|_| read_u32(input).map(f32::from_bits))
+        .collect()
+}
+
+fn read_vec3s<R: Read>(input: &mut R, n: usize) -> io::Result<Vec<Vec3>> {
+    let values = read_f32s(input, 3 * n)?;
+    Ok(values
+        .chunks(3)
+        .map(|c| Vec3::new(c[0], c[1], c[2]))
+        .collect())
+}
+
+/// Return the lowercase extension of `path`.
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+}
+
+/// Return the path of the image holding the given AOV, next to the image at `path`.
+fn aov_path(path: &Path, aov: Aov) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match extension(path) {
+        Some(ext) => format!("{}.{}.{}", stem, aov.name(), ext),
+        None => format!("{}.{}", stem, aov.name()),
+    };
+    path.with_file_name(name)
+}
+
+fn to_rgb8<F>(width: usize, height: usize, pixels: &[Vec3], to_rgb8: F) -> RgbImage
+where
+    F: Fn(&Vec3) -> [u8; 3],
+{
+    let data = pixels.iter().flat_map(to_rgb8).collect();
+    RgbImage::from_raw(width as u32, height as u32, data).unwrap()
+}
+
+/// Save an image in the format given by the extension of `path`, using `to_rgb8` to convert the
+/// pixels for 8-bit formats.
+fn save_pixels<F>(
+    path: &Path,
+    width: usize,
+    height: usize,
+    pixels: &[Vec3],
+    to_rgb8_fn: F,
+) -> ImageResult<()>
+where
+    F: Fn(&Vec3) -> [u8; 3],
+{
+    match extension(path).as_deref() {
+        Some("hdr") => {
+            let out = BufWriter::new(File::create(path)?);
+            let pixels: Vec<Rgb<f32>> = pixels
+                .iter()
+                .map(|col| Rgb([col.r(), col.g(), col.b()]))
+                .collect();
+            HdrEncoder::new(out).encode(&pixels, width, height)
+        }
+        Some("pfm") => {
+            let mut out = BufWriter::new(File::create(path)?);
+            write_pfm(&mut out, width, height, pixels)?;
+            Ok(out.flush()?)
+        }
+        _ => to_rgb8(width, height, pixels, to_rgb8_fn).save(path),
+    }
+}
+
+/// Write a binary PFM image, which stores the linear radiance as 32-bit floats.
+fn write_pfm<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    pixels: &[Vec3],
+) -> io::Result<()> {
+    // A negative scale means the floats are little-endian
+    write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+    // Rows are stored from the bottom of the image to the top
+    for row in pixels.chunks(width).rev() {
+        for col in row {
+            for c in 0..3 {
+                out.write_all(&col[c].to_le_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The pixels of the film which the samples of a tile contribute to. Tiles are filled
+/// independently, then added to the film with `Film::add_tile`.
+#[derive(Debug, Clone)]
+pub struct FilmTile {
+    /// The pixels covered by this tile, in film coordinates
+    bounds: Tile,
+    sums: Vec<Vec3>,
+    weights: Vec<f32>,
+    counts: Vec<u32>,
+    luminance_means: Vec<f32>,
+    luminance_m2s: Vec<f32>,
+    aovs: Vec<Aov>,
+    aov_sums: Vec<Vec<Vec3>>,
+    /// Number of rays traced to render the samples of the tile
+    rays: u64,
+}
+
+impl FilmTile {
+    pub fn new(bounds: Tile, aovs: &[Aov]) -> FilmTile {
+        let n = bounds.width() * bounds.height();
+        FilmTile {
+            bounds,
+            sums: vec![Vec3::default(); n],
+            weights: vec![0.0; n],
+            counts: vec![0; n],
+            luminance_means: vec![0.0; n],
+            luminance_m2s: vec![0.0; n],
+            aovs: aovs.to_vec(),
+            aov_sums: vec![vec![Vec3::default(); n]; aovs.len()],
+            rays: 0,
+        }
+    }
+
+    /// Create an empty tile covering the pixels of a `width` x `height` film which the samples
+    /// taken in `tile` can contribute to with a filter of the given radius.
+    pub fn padded(tile: &Tile, radius: f32, width: usize, height: usize, aovs: &[Aov]) -> FilmTile {
+        FilmTile::new(FilmTile::padded_bounds(tile, radius, width, height), aovs)
+    }
+
+    /// Return the bounds of the tiles created by `padded`.
+    pub fn padded_bounds(tile: &Tile, radius: f32, width: usize, height: usize) -> Tile {
+        let pad = f32::ceil(radius) as usize;
+        Tile {
+            x0: tile.x0.saturating_sub(pad),
+            y0: tile.y0.saturating_sub(pad),
+            x1: usize::min(tile.x1 + pad, width),
+            y1: usize::min(tile.y1 + pad, height),
+        }
+    }
+
+    /// The AOVs stored in this tile.
+    pub fn aovs(&self) -> &[Aov] {
+        &self.aovs
+    }
+
+    /// Number of rays traced to render the samples of the tile.
+    pub fn rays(&self) -> u64 {
+        self.rays
+    }
+
+    /// Count rays traced to render the samples of the tile.
+    pub fn add_rays(&mut self, rays: u64) {
+        self.rays += rays;
+    }
+
+    /// Write the samples accumulated in the tile, so that it can be restored by `read_state`.
+    pub fn write_state<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let bounds = &self.bounds;
+        for value in [bounds.x0, bounds.y0, bounds.x1, bounds.y1] {
+            write_u32(out, value as u32)?;
+        }
+        write_vec3s(out, &self.sums)?;
+        write_f32s(out, &self.weights)?;
+        for &count in &self.counts {
+            write_u32(out, count)?;
+        }
+        write_f32s(out, &self.luminance_means)?;
+        write_f32s(out, &self.luminance_m2s)?;
+        for sums in &self.aov_sums {
+            write_vec3s(out, sums)?;
+        }
+        out.write_all(&self.rays.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    /// Read a tile written by `write_state`, which must have the given bounds and store the given
+    /// AOVs. The bounds are checked before anything is allocated, as the tile may come from an
+    /// untrusted peer.
+    pub fn read_state<R: Read>(input: &mut R, bounds: &Tile, aovs: &[Aov]) -> io::Result<FilmTile> {
+        let mut values = [0; 4];
+        for value in &mut values {
+            *value = read_u32(input)? as usize;
+        }
+        let [x0, y0, x1, y1] = values;
+        if (Tile { x0, y0, x1, y1 }) != *bounds {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "tile covers ({}, {}) to ({}, {}) instead of ({}, {}) to ({}, {})",
+                    x0, y0, x1, y1, bounds.x0, bounds.y0, bounds.x1, bounds.y1
+                ),
+            ));
+        }
+
+        let mut tile = FilmTile::new(*bounds, aovs);
+        let n = tile.sums.len();
+        tile.sums = read_vec3s(input, n)?;
+        tile.weights = read_f32s(input, n)?;
+        tile.counts = (0..n).map(|_| read_u32(input)).collect::<io::Result<_>>()?;
+        tile.luminance_means = read_f32s(input, n)?;
+        tile.luminance_m2s = read_f32s(input, n)?;
+        for sums in &mut tile.aov_sums {
+            *sums = read_vec3s(input, n)?;
+        }
+        let mut rays = [0u8; 8];
+        input.read_exact(&mut rays)?;
+        tile.rays = u64::from_le_bytes(rays);
+
+        Ok(tile)
+    }
+
+    /// Splat the radiance `l` of a sample at the continuous film position `(x, y)` to the pixels
+    /// whose centre is within the filter's radius. Pixel `(i, j)` covers `[i, i + 1) x [j, j + 1)`.
+    pub fn add_sample(&mut self, x: f32, y: f32, l: &Vec3, filter: &dyn Filter) {
+        let radius = filter.radius();
+        let bounds = &self.bounds;
+        // Pixels whose centre is at most `radius` before the sample, and strictly less after
+        let x0 = usize::max(
+            (f32::floor(x - 0.5 - radius) + 1.0).max(0.0) as usize,
+            bounds.x0,
+        );
+        let x1 = usize::min(
+            (f32::floor(x - 0.5 + radius) + 1.0).max(0.0) as usize,
+            bounds.x1,
+        );
+        let y0 = usize::max(
+            (f32::floor(y - 0.5 - radius) + 1.0).max(0.0) as usize,
+            bounds.y0,
+        );
+        let y1 = usize::min(
+            (f32::floor(y - 0.5 + radius) + 1.0).max(0.0) as usize,
+            bounds.y1,
+        );
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let weight = filter.evaluate(px as f32 + 0.5 - x, py as f32 + 0.5 - y);
+                let index = (py - bounds.y0) * bounds.width() + (px - bounds.x0);
+                self.sums[index] += weight * l;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    /// Record that a sample of radiance `l` was taken in pixel `(x, y)`, with the given values
+    /// for the tile's AOVs. This keeps the per-pixel statistics which aren't affected by the
+    /// reconstruction filter.
+    pub fn add_pixel_sample(&mut self, x: usize, y: usize, l: &Vec3, aov_values: &[Vec3]) {
+        let index = (y - self.bounds.y0) * self.bounds.width() + (x - self.bounds.x0);
+        let n = self.counts[index] as f32 + 1.0;
+        let delta = l.luminance() - self.luminance_means[index];
+        self.luminance_means[index] += delta / n;
+        self.luminance_m2s[index] += delta * (l.luminance() - self.luminance_means[index]);
+        for ((aov, sums), value) in self.aovs.iter().zip(&mut self.aov_sums).zip(aov_values) {
+            if aov.is_averaged() {
+                sums[index] += *value;
+            } else if self.counts[index] == 0 {
+                sums[index] = *value;
+            }
+        }
+        self.counts[index] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterKind;
+
+    #[test]
+    fn pixels_with_small_or_negative_weights_are_black() {
+        let mut film = Film::new(4, 1, &[]);
+        let filter = FilterKind::Lanczos { tau: 3.0 }.create(3.0);
+        let tile = Tile {
+            x0: 0,
+            y0: 0,
+            x1: 1,
+            y1: 1,
+        };
+        let mut film_tile = film.tile(&tile, filter.radius());
+        let l = Vec3::new(1.0, 2.0, 3.0);
+        film_tile.add_sample(0.5, 0.5, &l, &*filter);
+        film.add_tile(&film_tile);
+
+        // The sample is on the zero crossing of pixel 1, and in the negative lobe of pixel 2
+        assert!((film.pixel(0, 0) - l).length() < 1e-5);
+        for x in 1..4 {
+            assert_eq!(film.pixel(x, 0), Vec3::default());
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Reconstruction filters, which weight the contribution of each camera sample to the pixels
+//! around it.
+use std::f32;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+pub trait Filter: Send + Sync {
+    /// Distance from the sample beyond which the filter is zero, in pixels.
+    fn radius(&self) -> f32;
+
+    /// Return the weight of a sample at offset `(x, y)` from a pixel's centre.
+    fn evaluate(&self, x: f32, y: f32) -> f32;
+}
+
+/// The different reconstruction filters available to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterKind {
+    /// Every sample within the radius has the same weight. With a radius of 0.5, each pixel is
+    /// the average of its own samples.
+    #[default]
+    Box,
+    /// Weight decreasing linearly with the distance
+    Tent,
+    /// Gaussian of the given falloff, shifted to reach zero at the radius
+    Gaussian { alpha: f32 },
+    /// Mitchell-Netravali cubic, with the usual `b = c = 1/3` being a good compromise between
+    /// blurring and ringing
+    Mitchell { b: f32, c: f32 },
+    /// Sinc windowed by a wider sinc, `tau` being the number of lobes of the window
+    Lanczos { tau: f32 },
+}
+
+impl FilterKind {
+    /// Create a filter of this kind with the given radius, in pixels.
+    pub fn create(&self, radius: f32) -> Box<dyn Filter> {
+        match *self {
+            FilterKind::Box => Box::new(BoxFilter::new(radius)),
+            FilterKind::Tent => Box::new(TentFilter::new(radius)),
+            FilterKind::Gaussian { alpha } => Box::new(GaussianFilter::new(radius, alpha)),
+            FilterKind::Mitchell { b, c } => Box::new(MitchellFilter::new(radius, b, c)),
+            FilterKind::Lanczos { tau } => Box::new(LanczosFilter::new(radius, tau)),
+        }
+    }
+
+    /// Return the radius usually given to filters of this kind, in pixels.
+    pub fn default_radius(&self) -> f32 {
+        match *self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian { .. } => 1.5,
+            FilterKind::Mitchell { .. } => 2.0,
+            FilterKind::Lanczos { tau } => tau,
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    /// Parse the name of a filter kind, which is given the usual parameters.
+    fn from_str(name: &str) -> Result<FilterKind, String> {
+        match name {
+            "box" => Ok(FilterKind::Box),
+            "tent" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian { alpha: 2.0 }),
+            "mitchell" => Ok(FilterKind::Mitchell {
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            }),
+            "lanczos" => Ok(FilterKind::Lanczos { tau: 3.0 }),
+            _ => Err(format!("unknown filter `{}`", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BoxFilter {
+    radius: f32,
+}
+
+impl BoxFilter {
+    pub fn new(radius: f32) -> BoxFilter {
+        BoxFilter { radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn evaluate(&self, _x: f32, _y: f32) -> f32 {
+        1.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TentFilter {
+    radius: f32,
+}
+
+impl TentFilter {
+    pub fn new(radius: f32) -> TentFilter {
+        TentFilter { radius }
+    }
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f32, y: f32) -> f32 {
+        f32::max(0.0, self.radius - x.abs()) * f32::max(0.0, self.radius - y.abs())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GaussianFilter {
+    radius: f32,
+    alpha: f32,
+    /// Value of the gaussian at the radius
+    exp_radius: f32,
+}
+
+impl GaussianFilter {
+    pub fn new(radius: f32, alpha: f32) -> GaussianFilter {
+        GaussianFilter {
+            radius,
+            alpha,
+            exp_radius: f32::exp(-alpha * radius * radius),
+        }
+    }
+
+    fn gaussian(&self, d: f32) -> f32 {
+        f32::max(0.0, f32::exp(-self.alpha * d * d) - self.exp_radius)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f32, y: f32) -> f32 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MitchellFilter {
+    radius: f32,
+    b: f32,
+    c: f32,
+}
+
+impl MitchellFilter {
+    pub fn new(radius: f32, b: f32, c: f32) -> MitchellFilter {
+        MitchellFilter { radius, b, c }
+    }
+
+    /// Evaluate the cubic, which is defined over [-2, 2].
+    fn mitchell_1d(&self, x: f32) -> f32 {
+        let (b, c) = (self.b, self.c);
+        let x = (2.0 * x / self.radius).abs();
+        let value = if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            (-b - 6.0 * c) * x * x * x
+                + (6.0 * b + 30.0 * c) * x * x
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c)
+        } else {
+            (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                + (6.0 - 2.0 * b)
+        };
+
+        value / 6.0
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f32, y: f32) -> f32 {
+        self.mitchell_1d(x) * self.mitchell_1d(y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LanczosFilter {
+    radius: f32,
+    tau: f32,
+}
+
+impl LanczosFilter {
+    pub fn new(radius: f32, tau: f32) -> LanczosFilter {
+        LanczosFilter { radius, tau }
+    }
+
+    fn windowed_sinc(&self, x: f32) -> f32 {
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.tau)
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f32, y: f32) -> f32 {
+        self.windowed_sinc(x) * self.windowed_sinc(y)
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    let x = (f32::consts::PI * x).abs();
+    if x < 1e-5 {
+        1.0
+    } else {
+        f32::sin(x) / x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [&str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+    #[test]
+    fn filters_are_symmetric_and_peak_at_the_centre() {
+        for name in KINDS {
+            let kind: FilterKind = name.parse().unwrap();
+            let filter = kind.create(kind.default_radius());
+            let r = filter.radius();
+            let centre = filter.evaluate(0.0, 0.0);
+            assert!(centre > 0.0, "{}", name);
+            for i in 0..=20 {
+                let x = r * i as f32 / 20.0;
+                for y in [0.0, 0.3 * r, r] {
+                    let value = filter.evaluate(x, y);
+                    assert_eq!(value, filter.evaluate(-x, y), "{}", name);
+                    assert_eq!(value, filter.evaluate(x, -y), "{}", name);
+                    assert_eq!(value, filter.evaluate(y, x), "{}", name);
+                    assert!(value <= centre, "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn filters_vanish_at_the_radius() {
+        for name in ["tent", "gaussian", "mitchell", "lanczos"] {
+            let kind: FilterKind = name.parse().unwrap();
+            let filter = kind.create(kind.default_radius());
+            let r = filter.radius();
+            assert!(filter.evaluate(r, 0.0).abs() < 1e-5, "{}", name);
+            assert!(filter.evaluate(0.0, r).abs() < 1e-5, "{}", name);
+        }
+    }
+
+    #[test]
+    fn box_filter_is_constant() {
+        let filter = FilterKind::Box.create(0.5);
+        assert_eq!(filter.evaluate(0.0, 0.0), 1.0);
+        assert_eq!(filter.evaluate(0.49, -0.49), 1.0);
+    }
+
+    #[test]
+    fn mitchell_filter_integrates_to_one() {
+        // The cubic is normalized over [-2, 2], i.e. over the radius scaled by two
+        let filter = MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0);
+        let n = 4000;
+        let dx = 4.0 / n as f32;
+        let integral: f32 = (0..n)
+            .map(|i| filter.mitchell_1d(-2.0 + (i as f32 + 0.5) * dx) * dx)
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-3, "{}", integral);
+    }
+
+    #[test]
+    fn lanczos_filter_is_zero_at_whole_pixels() {
+        let filter = FilterKind::Lanczos { tau: 3.0 }.create(3.0);
+        assert!((filter.evaluate(0.0, 0.0) - 1.0).abs() < 1e-6);
+        for x in [1.0, 2.0] {
+            assert!(filter.evaluate(x, 0.0).abs() < 1e-6);
+        }
+        assert!(filter.evaluate(1.5, 0.0) < 0.0);
+    }
+
+    #[test]
+    fn parses_filter_names() {
+        assert_eq!("box".parse(), Ok(FilterKind::Box));
+        assert_eq!("lanczos".parse(), Ok(FilterKind::Lanczos { tau: 3.0 }));
+        assert!("sinc".parse::<FilterKind>().is_err());
+    }
+}
@@ -4,6 +4,7 @@ use crate::aabb::Aabb;
 use crate::hitable::{FlipNormals, HitRecord, Hitable, XYRect, XZRect, YZRect};
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::Vec3;
 
 pub struct Boxx {
@@ -83,4 +84,19 @@ impl Hitable for Boxx {
         *aabb = Aabb::new(&self.pmin, &self.pmax);
         true
     }
+
+    fn is_emissive(&self) -> bool {
+        let list = &self.list_ptr[..];
+        list.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        let list = &self.list_ptr[..];
+        list.pdf_value(o, v, time)
+    }
+
+    fn random(&self, o: &Vec3, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let list = &self.list_ptr[..];
+        list.random(o, time, sampler)
+    }
 }
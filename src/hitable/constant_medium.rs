@@ -0,0 +1,147 @@
+use std::f32;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::{Isotropic, Material};
+use crate::random::ray_rng;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::vec::Vec3;
+
+/// Volume of constant density filling a closed boundary, such as smoke or fog. Rays travelling
+/// through it are scattered at a random distance following an exponential distribution, using the
+/// phase function given as its material. `hit` has no sampler, so that distance is drawn from a
+/// generator seeded by the ray and where it enters the boundary, which keeps renders
+/// deterministic.
+pub struct ConstantMedium {
+    boundary: Arc<dyn Hitable>,
+    density: f32,
+    phase_function: Arc<dyn Material>,
+}
+
+impl ConstantMedium {
+    /// Create a medium scattering light uniformly in all directions.
+    pub fn new(
+        boundary: Arc<dyn Hitable>,
+        density: f32,
+        albedo: Arc<dyn Texture>,
+    ) -> ConstantMedium {
+        ConstantMedium::with_phase(boundary, density, Arc::new(Isotropic::new(albedo)))
+    }
+
+    pub fn with_phase(
+        boundary: Arc<dyn Hitable>,
+        density: f32,
+        phase_function: Arc<dyn Material>,
+    ) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            density,
+            phase_function,
+        }
+    }
+}
+
+impl Hitable for ConstantMedium {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let mut rec1 = HitRecord::default();
+        let mut rec2 = HitRecord::default();
+        if !self
+            .boundary
+            .hit(r, f32::NEG_INFINITY, f32::INFINITY, &mut rec1)
+        {
+            return false;
+        }
+        if !self
+            .boundary
+            .hit(r, rec1.t + 0.0001, f32::INFINITY, &mut rec2)
+        {
+            return false;
+        }
+
+        let t_enter = f32::max(rec1.t, t_min);
+        let t_exit = f32::min(rec2.t, t_max);
+        if t_enter >= t_exit {
+            return false;
+        }
+        let t_enter = f32::max(t_enter, 0.0);
+
+        let ray_length = r.direction().length();
+        let distance_inside_boundary = (t_exit - t_enter) * ray_length;
+        // Media crossed by the same ray are told apart by where the ray enters them
+        let mut rng = ray_rng(r, u64::from(rec1.t.to_bits()));
+        let hit_distance = -(1.0 / self.density) * f32::ln(1.0 - rng.gen::<f32>());
+        if hit_distance > distance_inside_boundary {
+            return false;
+        }
+
+        rec.t = t_enter + hit_distance / ray_length;
+        rec.p = r.point_at_parameter(rec.t);
+        // Arbitrary, the phase function doesn't use it
+        rec.normal = Vec3::new(1.0, 0.0, 0.0);
+        rec.mat = Some(Arc::clone(&self.phase_function));
+        rec.u = 0.0;
+        rec.v = 0.0;
+
+        true
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32, aabb: &mut Aabb) -> bool {
+        self.boundary.bounding_box(t0, t1, aabb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::Sphere;
+    use crate::texture::ConstantTexture;
+
+    /// A medium filling the unit sphere centred on `(x, 0, 0)`.
+    fn medium(x: f32) -> ConstantMedium {
+        let white = Arc::new(ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0)));
+        let phase = Arc::new(Isotropic::new(white.clone()));
+        let boundary = Arc::new(Sphere::new(Vec3::new(x, 0.0, 0.0), 1.0, phase));
+        ConstantMedium::new(boundary, 0.5, white)
+    }
+
+    /// Return how far a ray parallel to the x axis, at height `y` and with the given seed,
+    /// travels in the medium filling the unit sphere centred on `(x, 0, 0)` before being
+    /// scattered, if it is.
+    fn scatter_distance(x: f32, y: f32, seed: u64) -> Option<f32> {
+        let origin = Vec3::new(0.0, y, 0.0);
+        let r = Ray::with_time(&origin, &Vec3::new(1.0, 0.0, 0.0), 0.0).with_seed(seed);
+        let mut rec = HitRecord::default();
+        let entry = x - f32::sqrt(1.0 - y * y);
+        medium(x)
+            .hit(&r, 0.001, f32::INFINITY, &mut rec)
+            .then_some(rec.t - entry)
+    }
+
+    #[test]
+    fn scattering_depends_on_the_seed() {
+        let distances: Vec<Option<f32>> = (0..20)
+            .map(|seed| scatter_distance(3.0, 0.0, seed))
+            .collect();
+        assert!(distances.iter().any(|d| *d != distances[0]));
+        assert_eq!(scatter_distance(3.0, 0.0, 7), distances[7]);
+    }
+
+    #[test]
+    fn media_along_a_ray_scatter_independently() {
+        let same = (0..50)
+            .map(|i| {
+                let y = 0.01 * i as f32;
+                (scatter_distance(3.0, y, i), scatter_distance(6.0, y, i))
+            })
+            .filter(|(near, far)| match (near, far) {
+                (Some(near), Some(far)) => (near - far).abs() < 1e-4,
+                _ => false,
+            })
+            .count();
+        assert_eq!(same, 0);
+    }
+}
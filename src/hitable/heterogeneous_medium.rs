@@ -0,0 +1,88 @@
+use std::f32;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::aabb::Aabb;
+use crate::density::Density;
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
+use crate::random::ray_rng;
+use crate::ray::Ray;
+use crate::vec::Vec3;
+
+/// Volume whose density varies in space, filling a closed boundary. The distance at which rays
+/// get scattered is found with delta tracking: tentative collisions are sampled as if the whole
+/// volume had the density's majorant, and each is accepted with probability `density /
+/// majorant`. The rejected ones act as null collisions, which keeps the result unbiased.
+pub struct HeterogeneousMedium {
+    boundary: Arc<dyn Hitable>,
+    density: Arc<dyn Density>,
+    phase_function: Arc<dyn Material>,
+}
+
+impl HeterogeneousMedium {
+    pub fn new(
+        boundary: Arc<dyn Hitable>,
+        density: Arc<dyn Density>,
+        phase_function: Arc<dyn Material>,
+    ) -> HeterogeneousMedium {
+        HeterogeneousMedium {
+            boundary,
+            density,
+            phase_function,
+        }
+    }
+}
+
+impl Hitable for HeterogeneousMedium {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let mut rec1 = HitRecord::default();
+        let mut rec2 = HitRecord::default();
+        if !self
+            .boundary
+            .hit(r, f32::NEG_INFINITY, f32::INFINITY, &mut rec1)
+        {
+            return false;
+        }
+        if !self
+            .boundary
+            .hit(r, rec1.t + 0.0001, f32::INFINITY, &mut rec2)
+        {
+            return false;
+        }
+
+        let t_enter = f32::max(f32::max(rec1.t, t_min), 0.0);
+        let t_exit = f32::min(rec2.t, t_max);
+        let majorant = self.density.max_value();
+        if t_enter >= t_exit || majorant <= 0.0 {
+            return false;
+        }
+
+        // Media crossed by the same ray are told apart by where the ray enters them
+        let mut rng = ray_rng(r, u64::from(rec1.t.to_bits()));
+        let ray_length = r.direction().length();
+        let mut t = t_enter;
+        loop {
+            t -= f32::ln(1.0 - rng.gen::<f32>()) / (majorant * ray_length);
+            if t >= t_exit {
+                return false;
+            }
+            let p = r.point_at_parameter(t);
+            if rng.gen::<f32>() * majorant < self.density.value(&p) {
+                rec.t = t;
+                rec.p = p;
+                // Arbitrary, the phase function doesn't use it
+                rec.normal = Vec3::new(1.0, 0.0, 0.0);
+                rec.mat = Some(Arc::clone(&self.phase_function));
+                rec.u = 0.0;
+                rec.v = 0.0;
+                return true;
+            }
+        }
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32, aabb: &mut Aabb) -> bool {
+        self.boundary.bounding_box(t0, t1, aabb)
+    }
+}
@@ -1,16 +1,21 @@
 mod boxx;
+mod constant_medium;
+mod heterogeneous_medium;
 mod rect;
 mod sphere;
 
 use std::sync::Arc;
 
 pub use self::boxx::*;
+pub use self::constant_medium::*;
+pub use self::heterogeneous_medium::*;
 pub use self::rect::*;
 pub use self::sphere::*;
 
 use crate::aabb::{surrounding_box, Aabb};
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec::Vec3;
 
 #[derive(Debug, Clone, Default)]
@@ -21,14 +26,34 @@ pub struct HitRecord {
     pub mat: Option<Arc<dyn Material>>,
     pub u: f32,
     pub v: f32,
+    /// Id of the top-level object which was hit, see `Tagged`
+    pub object_id: u32,
 }
 
-pub trait Hitable {
+pub trait Hitable: Send + Sync {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool;
     fn bounding_box(&self, t0: f32, t1: f32, aabb: &mut Aabb) -> bool;
+
+    /// Whether this object emits light and can be sampled as a light source with `random` and
+    /// `pdf_value`.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    /// Return the probability density, with respect to solid angle at `o`, of sampling the
+    /// direction `v` with `random` at time `time`.
+    fn pdf_value(&self, _o: &Vec3, _v: &Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    /// Return the (non-normalized) direction from `o` to a random point on this object, where it
+    /// is at time `time`.
+    fn random(&self, _o: &Vec3, _time: f32, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
 }
 
-impl<'a> Hitable for &'a [Arc<dyn Hitable>] {
+impl Hitable for &[Arc<dyn Hitable>] {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
         let mut temp_hit = HitRecord::default();
         let mut hit_anything = false;
@@ -65,6 +90,66 @@ impl<'a> Hitable for &'a [Arc<dyn Hitable>] {
 
         true
     }
+
+    fn is_emissive(&self) -> bool {
+        self.iter().any(|hitable| hitable.is_emissive())
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self
+            .iter()
+            .map(|hitable| hitable.pdf_value(o, v, time))
+            .sum();
+        sum / self.len() as f32
+    }
+
+    fn random(&self, o: &Vec3, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let index = (sampler.get_1d() * self.len() as f32) as usize;
+        self[index.min(self.len() - 1)].random(o, time, sampler)
+    }
+}
+
+/// Wrapper giving an id to an object, which is reported in `HitRecord::object_id` for every hit
+/// on the object or any of its parts.
+pub struct Tagged {
+    ptr: Arc<dyn Hitable>,
+    id: u32,
+}
+
+impl Tagged {
+    pub fn new(ptr: Arc<dyn Hitable>, id: u32) -> Tagged {
+        Tagged { ptr, id }
+    }
+}
+
+impl Hitable for Tagged {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        if self.ptr.hit(r, t_min, t_max, rec) {
+            rec.object_id = self.id;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32, aabb: &mut Aabb) -> bool {
+        self.ptr.bounding_box(t0, t1, aabb)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.ptr.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        self.ptr.pdf_value(o, v, time)
+    }
+
+    fn random(&self, o: &Vec3, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        self.ptr.random(o, time, sampler)
+    }
 }
 
 pub struct FlipNormals {
@@ -88,4 +173,16 @@ impl Hitable for FlipNormals {
     fn bounding_box(&self, t0: f32, t1: f32, aabb: &mut Aabb) -> bool {
         self.ptr.bounding_box(t0, t1, aabb)
     }
+
+    fn is_emissive(&self) -> bool {
+        self.ptr.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        self.ptr.pdf_value(o, v, time)
+    }
+
+    fn random(&self, o: &Vec3, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        self.ptr.random(o, time, sampler)
+    }
 }
@@ -4,7 +4,8 @@ use crate::aabb::Aabb;
 use crate::hitable::{HitRecord, Hitable};
 use crate::material::Material;
 use crate::ray::Ray;
-use crate::Vec3;
+use crate::sampler::Sampler;
+use crate::vec::{dot, Vec3};
 
 #[derive(Debug)]
 pub struct XYRect {
@@ -58,6 +59,32 @@ impl Hitable for XYRect {
         );
         true
     }
+
+    fn is_emissive(&self) -> bool {
+        self.mp.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        let mut rec = HitRecord::default();
+        if self.hit(&Ray::with_time(o, v, time), 0.001, f32::MAX, &mut rec) {
+            let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+            let distance_squared = rec.t * rec.t * v.squared_length();
+            let cosine = f32::abs(dot(v, &Vec3::new(0.0, 0.0, 1.0)) / v.length());
+            distance_squared / (cosine * area)
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, o: &Vec3, _time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let random_point = Vec3::new(
+            self.x0 + u1 * (self.x1 - self.x0),
+            self.y0 + u2 * (self.y1 - self.y0),
+            self.k,
+        );
+        random_point - *o
+    }
 }
 
 #[derive(Debug)]
@@ -112,6 +139,32 @@ impl Hitable for XZRect {
         );
         true
     }
+
+    fn is_emissive(&self) -> bool {
+        self.mp.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        let mut rec = HitRecord::default();
+        if self.hit(&Ray::with_time(o, v, time), 0.001, f32::MAX, &mut rec) {
+            let area = (self.x1 - self.x0) * (self.z1 - self.z0);
+            let distance_squared = rec.t * rec.t * v.squared_length();
+            let cosine = f32::abs(dot(v, &Vec3::new(0.0, 1.0, 0.0)) / v.length());
+            distance_squared / (cosine * area)
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, o: &Vec3, _time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let random_point = Vec3::new(
+            self.x0 + u1 * (self.x1 - self.x0),
+            self.k,
+            self.z0 + u2 * (self.z1 - self.z0),
+        );
+        random_point - *o
+    }
 }
 
 #[derive(Debug)]
@@ -166,4 +219,30 @@ impl Hitable for YZRect {
         );
         true
     }
+
+    fn is_emissive(&self) -> bool {
+        self.mp.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        let mut rec = HitRecord::default();
+        if self.hit(&Ray::with_time(o, v, time), 0.001, f32::MAX, &mut rec) {
+            let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+            let distance_squared = rec.t * rec.t * v.squared_length();
+            let cosine = f32::abs(dot(v, &Vec3::new(1.0, 0.0, 0.0)) / v.length());
+            distance_squared / (cosine * area)
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, o: &Vec3, _time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let random_point = Vec3::new(
+            self.k,
+            self.y0 + u1 * (self.y1 - self.y0),
+            self.z0 + u2 * (self.z1 - self.z0),
+        );
+        random_point - *o
+    }
 }
@@ -4,7 +4,9 @@ use std::sync::Arc;
 use crate::aabb::{surrounding_box, Aabb};
 use crate::hitable::{HitRecord, Hitable};
 use crate::material::Material;
+use crate::onb::Onb;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec::{dot, Vec3};
 
 pub struct Sphere {
@@ -67,6 +69,35 @@ impl Hitable for Sphere {
 
         true
     }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3, time: f32) -> f32 {
+        let mut rec = HitRecord::default();
+        let distance_squared = (self.center - *o).squared_length();
+        if distance_squared <= self.radius * self.radius
+            || !self.hit(&Ray::with_time(o, v, time), 0.001, f32::MAX, &mut rec)
+        {
+            return 0.0;
+        }
+        let cos_theta_max = f32::sqrt(1.0 - self.radius * self.radius / distance_squared);
+        let solid_angle = 2.0 * f32::consts::PI * (1.0 - cos_theta_max);
+
+        1.0 / solid_angle
+    }
+
+    fn random(&self, o: &Vec3, _time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        // Sample the cone of directions subtended by the sphere, as seen from `o`
+        let direction = self.center - *o;
+        let distance_squared = direction.squared_length();
+        if distance_squared <= self.radius * self.radius {
+            return direction;
+        }
+        let uvw = Onb::from_w(&direction);
+        uvw.local(&random_to_sphere(self.radius, distance_squared, sampler))
+    }
 }
 
 pub struct MovingSphere {
@@ -157,3 +188,15 @@ fn get_sphere_uv(p: &Vec3) -> (f32, f32) {
 
     (u, v)
 }
+
+/// Return a random direction within the cone subtended by a sphere of the given radius, at the
+/// given squared distance along the z axis.
+fn random_to_sphere(radius: f32, distance_squared: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.get_2d();
+    let cos_theta_max = f32::sqrt(1.0 - radius * radius / distance_squared);
+    let z = 1.0 + r2 * (cos_theta_max - 1.0);
+    let phi = 2.0 * f32::consts::PI * r1;
+    let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+
+    Vec3::new(f32::cos(phi) * sin_theta, f32::sin(phi) * sin_theta, z)
+}
@@ -0,0 +1,393 @@
+//! Integrators compute the radiance arriving along a camera ray.
+use std::f32;
+
+use serde::Deserialize;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatterPdf, ScatterRecord};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec::Vec3;
+
+pub trait Integrator: Send + Sync {
+    /// Return the radiance arriving at the origin of `r` from its direction.
+    fn li(&self, r: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3;
+}
+
+/// The different integrators available to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegratorKind {
+    /// Follow the scattered rays until they happen to hit a light
+    #[default]
+    Naive,
+    /// Sample the lights directly at each diffuse bounce
+    LightSampling,
+    /// Combine light sampling and BSDF sampling with multiple importance sampling
+    Mis(MisHeuristic),
+}
+
+impl IntegratorKind {
+    pub fn create(&self, depth: PathDepth) -> Box<dyn Integrator> {
+        match self {
+            IntegratorKind::Naive => Box::new(NaiveIntegrator::new(depth)),
+            IntegratorKind::LightSampling => Box::new(LightSamplingIntegrator::new(depth)),
+            IntegratorKind::Mis(heuristic) => Box::new(MisIntegrator::new(depth, *heuristic)),
+        }
+    }
+}
+
+/// Controls the number of bounces along a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathDepth {
+    /// Number of bounces after which paths start being randomly terminated with Russian
+    /// roulette, depending on how much light they can still carry
+    pub min_depth: u32,
+    /// Number of bounces after which paths are always terminated
+    pub max_depth: u32,
+}
+
+impl Default for PathDepth {
+    fn default() -> PathDepth {
+        PathDepth {
+            min_depth: 3,
+            max_depth: 50,
+        }
+    }
+}
+
+impl PathDepth {
+    /// Decide whether a path should continue after `depth` bounces. When Russian roulette
+    /// terminates paths with probability `q`, the surviving ones have their throughput scaled by
+    /// `1 / (1 - q)`, which keeps the estimate unbiased.
+    fn continue_path(&self, depth: u32, throughput: &mut Vec3, sampler: &mut dyn Sampler) -> bool {
+        if depth + 1 >= self.max_depth {
+            return false;
+        }
+        if depth + 1 < self.min_depth {
+            return true;
+        }
+        let survival = f32::min(throughput.max_component(), 0.95);
+        if survival <= 0.0 || sampler.get_1d() >= survival {
+            return false;
+        }
+        *throughput /= survival;
+        true
+    }
+}
+
+/// Integrator which only finds lights by chance, when a scattered ray happens to hit them.
+#[derive(Debug, Clone, Copy)]
+pub struct NaiveIntegrator {
+    depth: PathDepth,
+}
+
+impl NaiveIntegrator {
+    pub fn new(depth: PathDepth) -> NaiveIntegrator {
+        NaiveIntegrator { depth }
+    }
+}
+
+impl Integrator for NaiveIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut radiance = Vec3::default();
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        let mut rec = HitRecord::default();
+        let mut depth = 0;
+        loop {
+            if !scene.hit(&ray, 0.001, f32::INFINITY, &mut rec) {
+                radiance += throughput * scene.environment.le(ray.direction());
+                break;
+            }
+            let mat = match rec.mat.as_deref() {
+                Some(mat) => mat,
+                None => break,
+            };
+            radiance += throughput * mat.emitted(rec.u, rec.v, &rec.p);
+
+            // if we hit a surface with a material, continue along the scattered ray
+            let (scattered, weight) = match mat
+                .scatter(&ray, &rec, sampler)
+                .and_then(|srec| sample_scatter(mat, &ray, &rec, srec, sampler))
+            {
+                Some(scattered) => scattered,
+                None => break,
+            };
+            throughput = throughput * weight;
+            if !self.depth.continue_path(depth, &mut throughput, sampler) {
+                break;
+            }
+            ray = scattered;
+            depth += 1;
+        }
+
+        radiance
+    }
+}
+
+/// Pick the direction of the scattered ray from the given `ScatterRecord`, and return it with the
+/// weight by which the light coming from that direction needs to be multiplied.
+fn sample_scatter(
+    mat: &dyn Material,
+    r: &Ray,
+    rec: &HitRecord,
+    srec: ScatterRecord,
+    sampler: &mut dyn Sampler,
+) -> Option<(Ray, Vec3)> {
+    match srec.pdf {
+        ScatterPdf::Specular(scattered) => Some((scattered, srec.attenuation)),
+        ScatterPdf::Diffuse(pdf) => {
+            let scattered = r.spawn(&rec.p, &pdf.generate(sampler));
+            let pdf_value = pdf.value(scattered.direction());
+            let weight = if pdf_value > 0.0 {
+                mat.eval(r, rec, &scattered) / pdf_value
+            } else {
+                Vec3::default()
+            };
+            if weight != Vec3::default() {
+                Some((scattered, weight))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Pick a point on one of the scene's lights (or a direction towards the environment), and return
+/// the light it sends towards `rec.p` and scatters along `-r`, divided by the density of the
+/// chosen direction. The ray towards the light and that density are returned as well.
+fn sample_lights(
+    scene: &Scene,
+    mat: &dyn Material,
+    r: &Ray,
+    rec: &HitRecord,
+    sampler: &mut dyn Sampler,
+) -> Option<(Vec3, Ray, f32)> {
+    let direction = scene.random_light_direction(&rec.p, r.time(), sampler);
+    let to_light = r.spawn(&rec.p, &direction);
+    let light_pdf_value = scene.light_pdf_value(&rec.p, to_light.direction(), r.time());
+    if light_pdf_value <= 0.0 {
+        return None;
+    }
+    let mut light_rec = HitRecord::default();
+    let le = if scene.hit(&to_light, 0.001, f32::INFINITY, &mut light_rec) {
+        let light_mat = light_rec.mat.as_deref()?;
+        light_mat.emitted(light_rec.u, light_rec.v, &light_rec.p)
+    } else if scene.environment.is_sampleable() {
+        scene.environment.le(to_light.direction())
+    } else {
+        return None;
+    };
+    let contribution = mat.eval(r, rec, &to_light) * le / light_pdf_value;
+
+    Some((contribution, to_light, light_pdf_value))
+}
+
+/// Integrator performing next-event estimation: at each non-specular bounce, a point is picked on
+/// one of the scene's lights and its contribution is added if it isn't occluded. Light reached by
+/// the scattered ray after such a bounce is then ignored, as it has already been accounted for.
+///
+/// Only the lights collected in `Scene::lights` are sampled, so emissive objects which can't be
+/// sampled (e.g. moving spheres) only contribute through specular bounces. The environment is
+/// sampled like a light if it supports it, otherwise it is always counted when a ray escapes the
+/// scene.
+#[derive(Debug, Clone, Copy)]
+pub struct LightSamplingIntegrator {
+    depth: PathDepth,
+}
+
+impl LightSamplingIntegrator {
+    pub fn new(depth: PathDepth) -> LightSamplingIntegrator {
+        LightSamplingIntegrator { depth }
+    }
+}
+
+impl Integrator for LightSamplingIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut radiance = Vec3::default();
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        let mut rec = HitRecord::default();
+        let mut depth = 0;
+        let mut count_emitted = true;
+        loop {
+            if !scene.hit(&ray, 0.001, f32::INFINITY, &mut rec) {
+                if count_emitted || !scene.environment.is_sampleable() {
+                    radiance += throughput * scene.environment.le(ray.direction());
+                }
+                break;
+            }
+            let mat = match rec.mat.as_deref() {
+                Some(mat) => mat,
+                None => break,
+            };
+            if count_emitted {
+                radiance += throughput * mat.emitted(rec.u, rec.v, &rec.p);
+            }
+
+            let srec = match mat.scatter(&ray, &rec, sampler) {
+                Some(srec) => srec,
+                None => break,
+            };
+            let sample_lights_here = scene.has_lights() && !srec.is_specular();
+            if sample_lights_here {
+                if let Some((direct, _, _)) = sample_lights(scene, mat, &ray, &rec, sampler) {
+                    radiance += throughput * direct;
+                }
+            }
+
+            let (scattered, weight) = match sample_scatter(mat, &ray, &rec, srec, sampler) {
+                Some(scattered) => scattered,
+                None => break,
+            };
+            throughput = throughput * weight;
+            if !self.depth.continue_path(depth, &mut throughput, sampler) {
+                break;
+            }
+            count_emitted = !sample_lights_here;
+            ray = scattered;
+            depth += 1;
+        }
+
+        radiance
+    }
+}
+
+/// Heuristics used to weight samples when combining several sampling strategies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MisHeuristic {
+    Balance,
+    #[default]
+    Power,
+}
+
+impl MisHeuristic {
+    /// Return the weight of a sample generated by a strategy with density `pdf_f`, when another
+    /// strategy would have generated it with density `pdf_g`.
+    pub fn weight(&self, pdf_f: f32, pdf_g: f32) -> f32 {
+        if pdf_f.is_infinite() {
+            return 1.0;
+        }
+        let (f, g) = match self {
+            MisHeuristic::Balance => (pdf_f, pdf_g),
+            MisHeuristic::Power => (pdf_f * pdf_f, pdf_g * pdf_g),
+        };
+        if f + g > 0.0 {
+            f / (f + g)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Integrator which, at each non-specular bounce, both samples the lights and follows a ray
+/// sampled from the BSDF, weighting both contributions with multiple importance sampling. Light
+/// sampling works best for large, rough surfaces and small lights, while BSDF sampling is better
+/// for glossy surfaces and large lights; MIS keeps the best of both.
+#[derive(Debug, Clone, Copy)]
+pub struct MisIntegrator {
+    depth: PathDepth,
+    heuristic: MisHeuristic,
+}
+
+impl MisIntegrator {
+    pub fn new(depth: PathDepth, heuristic: MisHeuristic) -> MisIntegrator {
+        MisIntegrator { depth, heuristic }
+    }
+
+    /// Weight the light `emitted` towards the origin of `r`, found by following a direction
+    /// sampled from the BSDF with the density given in `prev`, against the chance of light
+    /// sampling finding it too.
+    fn weight_emitted(
+        &self,
+        scene: &Scene,
+        r: &Ray,
+        prev: Option<(Vec3, f32)>,
+        emitted: Vec3,
+    ) -> Vec3 {
+        match prev {
+            Some((origin, bsdf_pdf)) if emitted != Vec3::default() => {
+                let light_pdf = scene.light_pdf_value(&origin, r.direction(), r.time());
+                self.heuristic.weight(bsdf_pdf, light_pdf) * emitted
+            }
+            _ => emitted,
+        }
+    }
+}
+
+impl Integrator for MisIntegrator {
+    fn li(&self, r: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut radiance = Vec3::default();
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        let mut rec = HitRecord::default();
+        let mut depth = 0;
+        // Origin of the current ray and the density with which it was sampled from the BSDF, if
+        // it comes from a non-specular bounce
+        let mut prev: Option<(Vec3, f32)> = None;
+        loop {
+            if !scene.hit(&ray, 0.001, f32::INFINITY, &mut rec) {
+                let mut emitted = scene.environment.le(ray.direction());
+                if scene.environment.is_sampleable() {
+                    emitted = self.weight_emitted(scene, &ray, prev, emitted);
+                }
+                radiance += throughput * emitted;
+                break;
+            }
+            let mat = match rec.mat.as_deref() {
+                Some(mat) => mat,
+                None => break,
+            };
+
+            let emitted = mat.emitted(rec.u, rec.v, &rec.p);
+            radiance += throughput * self.weight_emitted(scene, &ray, prev, emitted);
+
+            let srec = match mat.scatter(&ray, &rec, sampler) {
+                Some(srec) => srec,
+                None => break,
+            };
+            let scattered = match srec.pdf {
+                ScatterPdf::Specular(scattered) => {
+                    throughput = throughput * srec.attenuation;
+                    prev = None;
+                    scattered
+                }
+                ScatterPdf::Diffuse(pdf) => {
+                    if scene.has_lights() {
+                        if let Some((direct, to_light, light_pdf)) =
+                            sample_lights(scene, mat, &ray, &rec, sampler)
+                        {
+                            let bsdf_pdf = mat.pdf(&ray, &rec, &to_light);
+                            let weight = self.heuristic.weight(light_pdf, bsdf_pdf);
+                            radiance += weight * (throughput * direct);
+                        }
+                    }
+
+                    let scattered = ray.spawn(&rec.p, &pdf.generate(sampler));
+                    let bsdf_pdf = pdf.value(scattered.direction());
+                    if bsdf_pdf <= 0.0 {
+                        break;
+                    }
+                    let weight = mat.eval(&ray, &rec, &scattered) / bsdf_pdf;
+                    if weight == Vec3::default() {
+                        break;
+                    }
+                    throughput = throughput * weight;
+                    prev = Some((rec.p, bsdf_pdf));
+                    scattered
+                }
+            };
+
+            if !self.depth.continue_path(depth, &mut throughput, sampler) {
+                break;
+            }
+            ray = scattered;
+            depth += 1;
+        }
+
+        radiance
+    }
+}
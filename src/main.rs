@@ -9,196 +9,88 @@
 )]
 
 mod aabb;
+mod animation;
+mod aov;
 mod bvh;
 mod camera;
+mod checkpoint;
+mod denoise;
+mod density;
+mod distributed;
+mod distribution;
+mod environment;
+mod film;
+mod filter;
 mod hitable;
+mod integrator;
 mod material;
+mod onb;
+mod pdf;
 mod perlin;
+mod preview;
+mod random;
 mod ray;
+mod render;
+mod sampler;
+mod scene;
+mod scene_file;
+mod service;
 mod texture;
+mod tonemap;
 mod vec;
 
 use std::f32;
-use std::fs::File;
-use std::io::{BufWriter, Write};
 use std::sync::Arc;
 
-use log::info;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use log::{error, warn, LevelFilter};
 use rand::Rng;
 
-use crate::bvh::BvhNode;
+use crate::animation::{render_animation, save_gif, Animation, CameraRig, Keyframes};
 use crate::camera::Camera;
+use crate::checkpoint::Checkpoint;
+use crate::distributed::Coordinator;
+use crate::environment::{Environment, GradientEnvironment};
+use crate::filter::FilterKind;
 use crate::hitable::*;
-use crate::material::{Dielectric, DiffuseLight, Lambertian, Material, Metal};
-use crate::ray::Ray;
+use crate::integrator::{IntegratorKind, MisHeuristic};
+use crate::material::{Dielectric, Lambertian, Material, Metal};
+use crate::preview::Preview;
+use crate::random::{seeded_rng, RenderRng};
+use crate::render::{
+    render_distributed, render_progressive, AdaptiveSampling, Progress, RenderSettings, Renderer,
+};
+use crate::sampler::{Sampler, SamplerKind};
+use crate::scene::Scene;
+use crate::scene_file::{SceneFile, SceneSettings};
+use crate::service::{SceneBuilder, SceneSource};
 use crate::texture::*;
+use crate::tonemap::{OutputTransform, ToneMapper};
 use crate::vec::Vec3;
 
-fn color(r: &Ray, world: &dyn Hitable, depth: u32) -> Vec3 {
-    let mut rec = HitRecord::default();
-    if world.hit(r, 0.001, f32::INFINITY, &mut rec) {
-        let mut scattered = Ray::default();
-        let mut attenuation = Vec3::default();
-        let emitted = rec.mat.as_ref().unwrap().emitted(rec.u, rec.v, &rec.p);
-        if depth < 50
-            && rec.mat.is_some()
-            && rec
-                .mat
-                .as_ref()
-                .cloned()
-                .unwrap()
-                .scatter(r, &rec, &mut attenuation, &mut scattered)
-        {
-            // if we hit a surface with a material, recurse along the scattered ray
-            emitted + attenuation * color(&scattered, world, depth + 1)
-        } else {
-            emitted
-        }
-    } else {
-        Vec3::default()
-    }
-}
-
-fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
-    let mut p;
-    loop {
-        p = 2. * Vec3::new(rng.gen(), rng.gen(), rng.gen()) - Vec3::new(1., 1., 1.);
-        if p.squared_length() < 1. {
-            break;
-        }
-    }
-
-    p
+fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    // pick a direction, then a radius so that the volume is uniformly covered
+    let r = f32::cbrt(sampler.get_1d());
+    r * random_unit_vector(sampler)
 }
 
-fn cornell_box() -> Vec<Arc<dyn Hitable>> {
-    let mut list: Vec<Arc<dyn Hitable>> = Vec::new();
-    let red: Arc<dyn Material> = Arc::new(Lambertian::constant(Vec3::new(0.65, 0.05, 0.05)));
-    let green: Arc<dyn Material> = Arc::new(Lambertian::constant(Vec3::new(0.12, 0.45, 0.15)));
-    let white: Arc<dyn Material> = Arc::new(Lambertian::constant(Vec3::new(0.73, 0.73, 0.73)));
-    let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
-        Vec3::new(15.0, 15.0, 15.0),
-    ))));
-
-    list.push(Arc::new(FlipNormals::new(Arc::new(YZRect::new(
-        0.0,
-        555.0,
-        0.0,
-        555.0,
-        555.0,
-        green.clone(),
-    )))));
-    list.push(Arc::new(YZRect::new(
-        0.0,
-        555.0,
-        0.0,
-        555.0,
-        0.0,
-        red.clone(),
-    )));
-    list.push(Arc::new(XZRect::new(
-        213.0,
-        343.0,
-        227.0,
-        332.0,
-        554.0,
-        light.clone(),
-    )));
-    list.push(Arc::new(FlipNormals::new(Arc::new(XZRect::new(
-        0.0,
-        555.0,
-        0.0,
-        555.0,
-        555.0,
-        white.clone(),
-    )))));
-    list.push(Arc::new(XZRect::new(
-        0.0,
-        555.0,
-        0.0,
-        555.0,
-        0.0,
-        white.clone(),
-    )));
-    list.push(Arc::new(FlipNormals::new(Arc::new(XYRect::new(
-        0.0,
-        555.0,
-        0.0,
-        555.0,
-        555.0,
-        white.clone(),
-    )))));
-    // boxes
-    list.push(Arc::new(Boxx::new(
-        Vec3::new(130.0, 0.0, 65.0),
-        Vec3::new(295.0, 165.0, 230.0),
-        white.clone(),
-    )));
-    list.push(Arc::new(Boxx::new(
-        Vec3::new(265.0, 0.0, 295.0),
-        Vec3::new(430.0, 330.0, 460.0),
-        white.clone(),
-    )));
+fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.get_2d();
+    let z = 1.0 - 2.0 * u1;
+    let r_xy = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+    let phi = 2.0 * f32::consts::PI * u2;
 
-    list
-}
-
-fn simple_light() -> Vec<Arc<dyn Hitable>> {
-    let perltext = Arc::new(NoiseTexture::new(4.0));
-    let list: Vec<Arc<dyn Hitable>> = vec![
-        Arc::new(Sphere::new(
-            Vec3::new(0.0, -1000.0, 0.0),
-            1000.0,
-            Arc::new(Lambertian::new(perltext.clone())),
-        )),
-        Arc::new(Sphere::new(
-            Vec3::new(0.0, 2.0, 0.0),
-            2.0,
-            Arc::new(Lambertian::new(perltext)),
-        )),
-        Arc::new(Sphere::new(
-            Vec3::new(0.0, 7.0, 0.0),
-            2.0,
-            Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
-                Vec3::new(4.0, 4.0, 4.0),
-            )))),
-        )),
-        Arc::new(XYRect::new(
-            3.0,
-            5.0,
-            1.0,
-            3.0,
-            -2.0,
-            Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
-                Vec3::new(4.0, 4.0, 4.0),
-            )))),
-        )),
-    ];
-
-    list
-}
-
-fn two_perlin_spheres() -> Vec<Arc<dyn Hitable>> {
-    let perltext = Arc::new(NoiseTexture::new(4.0));
-    let list = vec![
-        Arc::new(Sphere::new(
-            Vec3::new(0.0, -1000.0, 0.0),
-            1000.0,
-            Arc::new(Lambertian::new(perltext.clone())),
-        )) as Arc<dyn Hitable>,
-        Arc::new(Sphere::new(
-            Vec3::new(0.0, 2.0, 0.0),
-            2.0,
-            Arc::new(Lambertian::new(perltext)),
-        )) as Arc<dyn Hitable>,
-    ];
-
-    list
+    Vec3::new(r_xy * f32::cos(phi), r_xy * f32::sin(phi), z)
 }
 
-fn random_scene() -> Vec<Arc<dyn Hitable>> {
-    let mut rng = rand::thread_rng();
+fn random_scene(rng: &mut RenderRng) -> Vec<Arc<dyn Hitable>> {
     let n = 500;
     let mut list = Vec::with_capacity(n);
     list.push(Arc::new(Sphere::new(
@@ -277,55 +169,339 @@ fn random_scene() -> Vec<Arc<dyn Hitable>> {
     list
 }
 
+/// The camera of the scenes from "Ray Tracing: The Next Week", looking at the origin from
+/// `lookfrom`.
+fn outdoor_rig(lookfrom: Vec3, lookat: Vec3, aperture: f32) -> CameraRig {
+    CameraRig {
+        lookfrom: Keyframes::constant(lookfrom),
+        lookat: Keyframes::constant(lookat),
+        vup: Vec3::new(0.0, 1.0, 0.0),
+        fov: Keyframes::constant(20.0),
+        aperture,
+        focus_distance: Keyframes::constant(10.0),
+    }
+}
+
+/// The objects of a scene, the light around them, and the camera looking at them.
+type SceneDescription = (Vec<Arc<dyn Hitable>>, Arc<dyn Environment>, CameraRig);
+
+/// Directory of the scene files which can be given by name, without their extension.
+const SCENE_DIRECTORY: &str = "scenes";
+
+/// Return the description of the scene with the given name, or in the given scene file, along
+/// with the render settings it gives and an id identifying the scene.
+fn scene_description(
+    name: &str,
+    rng: &mut RenderRng,
+) -> Result<(SceneDescription, SceneSettings, u64), String> {
+    if name == "random_scene" {
+        let sky: Arc<dyn Environment> = Arc::new(GradientEnvironment::sky());
+        let lookfrom = Vec3::new(13.0, 2.0, 3.0);
+        let rig = outdoor_rig(lookfrom, Vec3::new(0.0, 0.0, 0.0), 0.1);
+        // The scene is generated from the seed, which the renders' fingerprints already cover
+        let id = 1;
+        return Ok(((random_scene(rng), sky, rig), SceneSettings::default(), id));
+    }
+    let path = Path::new(name);
+    let file = if path.extension().is_some() {
+        SceneFile::open(path)?
+    } else {
+        SceneFile::open(Path::new(SCENE_DIRECTORY).join(name).with_extension("toml"))?
+    };
+    let description = (file.objects, file.environment, file.rig);
+    Ok((description, file.settings, file.hash))
+}
+
+/// Build the described scene for a still image, along with the camera looking at it. Objects may
+/// move while the shutter is open, from time 0 to 1.
+fn still_scene(
+    (world, environment, rig): SceneDescription,
+    settings: &RenderSettings,
+    rng: &mut RenderRng,
+) -> (Scene, Camera) {
+    let camera = rig.camera(settings.width as f32 / settings.height as f32, 0.0, 1.0);
+    let scene = Scene::new(world, environment, 0.0, 1.0, rng);
+    (scene, camera)
+}
+
+/// Load the scene of a job submitted to the service, and return its settings and what builds it
+/// for a still image. Scenes are only read from the scenes directory.
+fn load_job_scene(source: &SceneSource) -> Result<(SceneSettings, SceneBuilder), String> {
+    let file = match source {
+        // The scene is generated from the seed of the job
+        SceneSource::Name(name) if name == "random_scene" => {
+            let builder: SceneBuilder = Box::new(|settings| {
+                let mut rng = seeded_rng(settings.seed);
+                let (description, _, _) = scene_description("random_scene", &mut rng).unwrap();
+                still_scene(description, settings, &mut rng)
+            });
+            return Ok((SceneSettings::default(), builder));
+        }
+        SceneSource::Name(name) => {
+            SceneFile::open(Path::new(SCENE_DIRECTORY).join(name).with_extension("toml"))?
+        }
+        SceneSource::Text(text) => SceneFile::parse_confined(text, Path::new(SCENE_DIRECTORY))
+            .map_err(|e| format!("{}:{}: {}", e.line, e.column, e.message))?,
+    };
+    let description = (file.objects, file.environment, file.rig);
+    let builder: SceneBuilder = Box::new(move |settings| {
+        still_scene(description, settings, &mut seeded_rng(settings.seed))
+    });
+    Ok((file.settings, builder))
+}
+
+const USAGE: &str = "\
+usage: rtiow                          render on this machine
+       rtiow --spawn <count>          render with <count> worker processes on this machine
+       rtiow --listen <address> <count>
+                                      render with <count> workers connecting to <address>
+       rtiow --worker <address>       render tiles for the coordinator at <address>
+       rtiow --serve <address>        render the jobs submitted over HTTP to <address>
+       rtiow --animate <frames>       render <frames> frames of an animation to out_0000.png, ...
+
+options:
+       --scene <name|file>            render a scene from scenes/ or a scene file, cornell_box
+                                      by default (cornell_animated for animations)
+       --resume <file>                save the render to <file> after each pass, and resume
+                                      from it if it exists
+       --preview                      draw the image in the terminal after each pass
+       --gif                          also write the frames of an animation to out.gif
+
+settings, overriding those of the scene file:
+       --samples <count>              samples per pixel, on average with adaptive sampling
+       --time-limit <seconds>         time after which the render stops
+       --noise-target <error>         mean relative error of the pixels below which the render
+                                      stops, e.g. 0.02
+       --filter <kind>                reconstruction filter: box, tent, gaussian, mitchell or
+                                      lanczos
+       --filter-radius <pixels>       radius of the filter, the usual one for its kind by default
+       --min-depth <bounces>          bounces after which paths may be terminated by Russian
+                                      roulette
+       --max-depth <bounces>          bounces after which paths are always terminated
+       --denoise <true|false>         whether to denoise the image
+       --denoise-strength <strength>  how aggressively the denoiser smooths noise out, 1 by
+                                      default
+       --crop <x0,y0,x1,y1>           render only part of the image, in pixels, or in fractions
+                                      of the image if given as decimals
+       --crop-full-frame <true|false> whether a cropped image keeps the full size, black outside
+                                      the window
+       --aovs <name,...>              AOVs to write next to the image, e.g. out.normal.png: albedo,
+                                      normal, depth, position, uv, id or samples";
+
+/// Remove the option `name` and its value from `args`, and return the value.
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> Option<&'a str> {
+    match args.iter().position(|&arg| arg == name) {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i + 2).nth(1).unwrap()),
+        Some(_) => usage(),
+        None => None,
+    }
+}
+
+/// Remove the options overriding the render settings from `args`, and return them both parsed
+/// and as given, to pass them on to workers.
+fn take_settings_options<'a>(args: &mut Vec<&'a str>) -> (SceneSettings, Vec<&'a str>) {
+    let mut given = Vec::new();
+    let mut take = |name: &'static str| {
+        let value = take_option(args, name);
+        if let Some(value) = value {
+            given.extend([name, value]);
+        }
+        value
+    };
+    let options = SceneSettings {
+        samples: take("--samples").map(parse_value),
+        time_limit: take("--time-limit").map(parse_value),
+        noise_target: take("--noise-target").map(parse_value),
+        filter: take("--filter").map(parse_value),
+        filter_radius: take("--filter-radius").map(parse_value),
+        min_depth: take("--min-depth").map(parse_value),
+        max_depth: take("--max-depth").map(parse_value),
+        denoise: take("--denoise").map(parse_value),
+        denoise_strength: take("--denoise-strength").map(parse_value),
+        crop: take("--crop").map(parse_value),
+        crop_full_frame: take("--crop-full-frame").map(parse_value),
+        aovs: take("--aovs").map(|names| names.split(',').map(parse_value).collect()),
+        ..Default::default()
+    };
+    (options, given)
+}
+
+fn parse_value<T: FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
 fn main() {
     env_logger::init();
 
-    // width
-    let nx = 400;
-    // height
-    let ny = 200;
-    // number of samples
-    let ns = 1000;
-
-    let file = File::create("out.ppm").unwrap();
-    let mut out = BufWriter::new(file);
-    writeln!(out, "P3\n{} {}\n255", nx, ny).unwrap();
-
-    info!("Rendering {}x{} image...", nx, ny);
-    let mut rng = rand::thread_rng();
-    let lookfrom = Vec3::new(278.0, 278.0, -800.0);
-    let lookat = Vec3::new(278.0, 278.0, 0.0);
-    let dist_focus = 10.0;
-    let camera = Camera::new(
-        lookfrom,
-        lookat,
-        Vec3::new(0.0, 1.0, 0.0),
-        40.0,
-        nx as f32 / ny as f32,
-        0.0,
-        0.0,
-        1.0,
-        dist_focus,
-    );
-    let mut world = cornell_box();
-    // let mut world = random_scene();
-    let bvh = BvhNode::new(&mut world[..], 0.0, 0.0);
-    for j in (0..ny).rev() {
-        for i in 0..nx {
-            let mut col = Vec3::default();
-            for _s in 0..ns {
-                let u = (i as f32 + rng.gen::<f32>()) / nx as f32;
-                let v = (j as f32 + rng.gen::<f32>()) / ny as f32;
-                let ray = camera.get_ray(u, v);
-                col += color(&ray, &bvh, 0);
+    let settings = RenderSettings {
+        width: 400,
+        height: 200,
+        samples: 1000,
+        seed: 42,
+        sampler: SamplerKind::Sobol,
+        integrator: IntegratorKind::Mis(MisHeuristic::Power),
+        filter: FilterKind::Mitchell {
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        },
+        filter_radius: 2.0,
+        adaptive: Some(AdaptiveSampling::default()),
+        ..Default::default()
+    };
+
+    let transform = OutputTransform {
+        exposure: 0.0,
+        tone_mapper: ToneMapper::Aces,
+    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let preview = args.contains(&"--preview");
+    let gif = args.contains(&"--gif");
+    args.retain(|&arg| arg != "--preview" && arg != "--gif");
+    let scene_name = take_option(&mut args, "--scene");
+    let checkpoint = take_option(&mut args, "--resume").map(PathBuf::from);
+    let (options, options_args) = take_settings_options(&mut args);
+    if let Err(e) = options.validate() {
+        error!("Invalid settings: {}", e);
+        process::exit(2);
+    }
+    let parse_count = |count: &str| count.parse().unwrap_or_else(|_| usage());
+    if let ["--serve", address] = args[..] {
+        if let Err(e) = service::serve(address, settings, options, transform, load_job_scene) {
+            error!("Service failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let animate = matches!(args[..], ["--animate", _]);
+    let scene_name = scene_name.unwrap_or(if animate {
+        "cornell_animated"
+    } else {
+        "cornell_box"
+    });
+    let mut rng = seeded_rng(settings.seed);
+    let (description, scene_settings, scene_id) = scene_description(scene_name, &mut rng)
+        .unwrap_or_else(|e| {
+            error!("Failed to load scene: {}", e);
+            process::exit(1);
+        });
+    let settings = RenderSettings {
+        checkpoint,
+        scene_id,
+        ..options.apply(&scene_settings.apply(&settings))
+    };
+    let transform = scene_settings.transform(&transform);
+    if let Some(crop) = &settings.crop {
+        let window = crop.bounds(settings.width, settings.height);
+        if window.width() == 0 || window.height() == 0 {
+            error!("Invalid settings: the crop window is outside the image");
+            process::exit(2);
+        }
+    }
+
+    if let ["--animate", frames] = args[..] {
+        let animation = Animation {
+            frames: parse_count(frames),
+            ..Default::default()
+        };
+        let (world, environment, rig) = description;
+        let mut images = Vec::new();
+        render_animation(
+            &world,
+            &environment,
+            &rig,
+            &settings,
+            &animation,
+            |frame, film| {
+                if let Err(e) = film.save(format!("out_{:04}.png", frame), &transform) {
+                    warn!("Failed to save frame {}: {}", frame, e);
+                }
+                if gif {
+                    images.push(film.to_rgb8(&transform));
+                }
+            },
+        );
+        if gif {
+            if let Err(e) = save_gif("out.gif", &images, animation.frame_rate) {
+                error!("Failed to save out.gif: {}", e);
+                process::exit(1);
             }
-            col /= ns as f32;
-            col = Vec3::new(f32::sqrt(col.r()), f32::sqrt(col.g()), f32::sqrt(col.b()));
-            col *= 255.99;
-            let ir = col[0] as u32;
-            let ig = col[1] as u32;
-            let ib = col[2] as u32;
-            writeln!(out, "{} {} {}", ir, ig, ib).unwrap();
         }
+        return;
     }
+
+    let (scene, camera) = still_scene(description, &settings, &mut rng);
+
+    // Workers and coordinators build the same scene, then split the work
+    let renderer = Renderer::new(&scene, &camera, &settings);
+    let coordinator = match args[..] {
+        [] => Ok(None),
+        ["--worker", address] => {
+            if let Err(e) = distributed::run_worker(address, &renderer) {
+                error!("Worker failed: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        ["--spawn", count] => {
+            let mut worker_args = vec!["--scene", scene_name];
+            worker_args.extend(&options_args);
+            Coordinator::spawn(parse_count(count), &worker_args, &renderer).map(Some)
+        }
+        ["--listen", address, count] => TcpListener::bind(address)
+            .and_then(|listener| Coordinator::accept(&listener, parse_count(count), &renderer))
+            .map(Some),
+        _ => usage(),
+    };
+    let mut coordinator = coordinator.unwrap_or_else(|e| {
+        error!("Failed to start workers: {}", e);
+        process::exit(1);
+    });
+
+    // Pick up where an interrupted render left off
+    let resume = settings.checkpoint.as_ref().and_then(|path| {
+        let checkpoint = match Checkpoint::open(path) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                error!("Failed to read checkpoint {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        };
+        if checkpoint.fingerprint != renderer.fingerprint() {
+            error!(
+                "Checkpoint {} is of another scene or other settings, remove it to start again",
+                path.display()
+            );
+            process::exit(1);
+        }
+        Some(checkpoint)
+    });
+    let mut preview = preview.then(|| {
+        // Messages would scroll the preview away, its progress bar replaces them
+        log::set_max_level(log::max_level().min(LevelFilter::Warn));
+        Preview::new()
+    });
+    let save_intermediate = |film: &film::Film, progress: &Progress| {
+        if let Some(preview) = &mut preview {
+            if let Err(e) = preview.draw(film, &transform, progress) {
+                warn!("Failed to draw preview: {}", e);
+            }
+        }
+        if let Err(e) = film.save("out.png", &transform) {
+            warn!("Failed to save intermediate image: {}", e);
+        }
+        ControlFlow::Continue(())
+    };
+    let film = match &mut coordinator {
+        Some(coordinator) => render_distributed(&renderer, resume, coordinator, save_intermediate),
+        None => render_progressive(&scene, &camera, &settings, resume, save_intermediate),
+    };
+    film.save("out.png", &transform).unwrap();
 }
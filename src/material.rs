@@ -1,26 +1,77 @@
+use std::f32;
 use std::fmt::Debug;
 use std::sync::Arc;
 
-use rand::{self, Rng};
-
 use crate::hitable::HitRecord;
-use crate::random_in_unit_sphere;
+use crate::pdf::{
+    henyey_greenstein, CosinePdf, FuzzyReflectionPdf, HenyeyGreensteinPdf, Pdf, UniformSpherePdf,
+};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::texture::{ConstantTexture, Texture};
 use crate::vec::{dot, unit_vector, Vec3};
 
-pub trait Material: Debug {
+/// How light gets scattered at a surface.
+pub enum ScatterPdf {
+    /// The light is scattered along a single direction, which has already been chosen. Such
+    /// materials can't be evaluated for other directions, so lights can't be sampled for them.
+    Specular(Ray),
+    /// The light is scattered in many directions, which should be sampled following this
+    /// distribution.
+    Diffuse(Box<dyn Pdf>),
+}
+
+/// The result of scattering a ray at a surface.
+pub struct ScatterRecord {
+    /// Fraction of the light which is scattered, for each channel. For diffuse materials this is
+    /// the albedo, and `Material::eval` should be used to weight a particular direction.
+    pub attenuation: Vec3,
+    pub pdf: ScatterPdf,
+}
+
+impl ScatterRecord {
+    pub fn is_specular(&self) -> bool {
+        matches!(self.pdf, ScatterPdf::Specular(_))
+    }
+}
+
+pub trait Material: Debug + Send + Sync {
+    /// Scatter the incoming ray `r_in` at the surface described by `rec`. Returns `None` if the
+    /// light is absorbed.
     fn scatter(
         &self,
-        r_in: &Ray,
-        rec: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool;
+        _r_in: &Ray,
+        _rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
+        None
+    }
 
     fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
         Vec3::default()
     }
+
+    /// Whether this material emits any light.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    /// Return the fraction of light reflected by the surface, as shown in albedo buffers.
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::default()
+    }
+
+    /// Evaluate the BSDF for light arriving from the direction of `scattered` and leaving along
+    /// `-r_in`, multiplied by the cosine term. Always zero for specular materials.
+    fn eval(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Vec3 {
+        Vec3::default()
+    }
+
+    /// Return the density with which `scatter` generates the direction of `scattered`. Always
+    /// zero for specular materials.
+    fn pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f32 {
+        0.0
+    }
 }
 
 /// Lambertian (diffuse) material. It scatters light uniformly in every direction (independently of
@@ -45,19 +96,31 @@ impl Lambertian {
 impl Material for Lambertian {
     fn scatter(
         &self,
-        r_in: &Ray,
+        _r_in: &Ray,
         rec: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
-        let target = rec.p + rec.normal + random_in_unit_sphere();
-        *scattered = Ray::with_time(&rec.p, &(target - rec.p), r_in.time());
-        *attenuation = self.albedo.value(rec.u, rec.v, &rec.p);
-        true
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: self.albedo.value(rec.u, rec.v, &rec.p),
+            pdf: ScatterPdf::Diffuse(Box::new(CosinePdf::new(&rec.normal))),
+        })
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.pdf(r_in, rec, scattered) * self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn pdf(&self, _r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f32 {
+        CosinePdf::new(&rec.normal).value(scattered.direction())
     }
 }
 
-/// Metal material
+/// Metal material. A fuzz of 0 gives a perfect mirror, while higher values give a glossy
+/// reflection.
 #[derive(Debug, Clone)]
 pub struct Metal {
     albedo: Vec3,
@@ -76,18 +139,47 @@ impl Material for Metal {
         &self,
         r_in: &Ray,
         rec: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
         let reflected = reflect(&unit_vector(r_in.direction()), &rec.normal);
-        *scattered = Ray::with_time(
-            &rec.p,
-            &(reflected + self.fuzz * random_in_unit_sphere()),
-            r_in.time(),
-        );
-        *attenuation = self.albedo;
+        if self.fuzz > 0.0 {
+            return Some(ScatterRecord {
+                attenuation: self.albedo,
+                pdf: ScatterPdf::Diffuse(Box::new(FuzzyReflectionPdf::new(&reflected, self.fuzz))),
+            });
+        }
+
+        let scattered = r_in.spawn(&rec.p, &reflected);
+        if dot(scattered.direction(), &rec.normal) > 0.0 {
+            Some(ScatterRecord {
+                attenuation: self.albedo,
+                pdf: ScatterPdf::Specular(scattered),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        // Rays scattered below the surface are absorbed
+        if dot(scattered.direction(), &rec.normal) > 0.0 {
+            self.pdf(r_in, rec, scattered) * self.albedo
+        } else {
+            Vec3::default()
+        }
+    }
 
-        dot(scattered.direction(), &rec.normal) > 0.0
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f32 {
+        if self.fuzz > 0.0 {
+            let reflected = reflect(&unit_vector(r_in.direction()), &rec.normal);
+            FuzzyReflectionPdf::new(&reflected, self.fuzz).value(scattered.direction())
+        } else {
+            0.0
+        }
     }
 }
 
@@ -107,13 +199,11 @@ impl Material for Dielectric {
         &self,
         r_in: &Ray,
         rec: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
         let outward_normal;
         let ni_over_nt;
         let cosine;
-        *attenuation = Vec3::new(1.0, 1.0, 1.0);
         let dir_dot_n = dot(r_in.direction(), &rec.normal);
         if dir_dot_n > 0.0 {
             outward_normal = -&rec.normal;
@@ -138,13 +228,20 @@ impl Material for Dielectric {
             1.0
         };
 
-        if rand::thread_rng().gen::<f32>() < reflect_prob {
-            *scattered = Ray::with_time(&rec.p, &reflected, r_in.time());
+        let scattered = if sampler.get_1d() < reflect_prob {
+            r_in.spawn(&rec.p, &reflected)
         } else {
-            *scattered = Ray::with_time(&rec.p, &refracted, r_in.time());
-        }
+            r_in.spawn(&rec.p, &refracted)
+        };
 
-        true
+        Some(ScatterRecord {
+            attenuation: Vec3::new(1.0, 1.0, 1.0),
+            pdf: ScatterPdf::Specular(scattered),
+        })
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
     }
 }
 
@@ -160,18 +257,97 @@ impl DiffuseLight {
 }
 
 impl Material for DiffuseLight {
+    fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        self.emit.value(u, v, p)
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+}
+
+/// Phase function scattering light uniformly in all directions, for use inside volumes.
+#[derive(Debug, Clone)]
+pub struct Isotropic {
+    albedo: Arc<dyn Texture>,
+}
+
+impl Isotropic {
+    pub fn new(albedo: Arc<dyn Texture>) -> Isotropic {
+        Isotropic { albedo }
+    }
+}
+
+impl Material for Isotropic {
     fn scatter(
         &self,
         _r_in: &Ray,
-        _rec: &HitRecord,
-        _attenuation: &mut Vec3,
-        _scattered: &mut Ray,
-    ) -> bool {
-        false
+        rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: self.albedo.value(rec.u, rec.v, &rec.p),
+            pdf: ScatterPdf::Diffuse(Box::new(UniformSpherePdf)),
+        })
     }
 
-    fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
-        self.emit.value(u, v, p)
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.pdf(r_in, rec, scattered) * self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn pdf(&self, _r_in: &Ray, _rec: &HitRecord, scattered: &Ray) -> f32 {
+        UniformSpherePdf.value(scattered.direction())
+    }
+}
+
+/// Henyey-Greenstein phase function, for use inside volumes. The anisotropy `g`, between -1 and 1,
+/// controls whether light is mostly scattered forward (`g > 0`) or backward (`g < 0`).
+#[derive(Debug, Clone)]
+pub struct HenyeyGreenstein {
+    albedo: Arc<dyn Texture>,
+    g: f32,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(albedo: Arc<dyn Texture>, g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein {
+            albedo,
+            g: g.clamp(-0.99, 0.99),
+        }
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: self.albedo.value(rec.u, rec.v, &rec.p),
+            pdf: ScatterPdf::Diffuse(Box::new(HenyeyGreensteinPdf::new(r_in.direction(), self.g))),
+        })
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.pdf(r_in, rec, scattered) * self.albedo.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn pdf(&self, r_in: &Ray, _rec: &HitRecord, scattered: &Ray) -> f32 {
+        let cos_theta = dot(
+            &unit_vector(r_in.direction()),
+            &unit_vector(scattered.direction()),
+        );
+        henyey_greenstein(cos_theta, self.g)
     }
 }
 
@@ -0,0 +1,40 @@
+use crate::vec::{cross, unit_vector, Vec3};
+
+/// Orthonormal basis, used to express directions relative to a surface normal.
+#[derive(Debug, Clone)]
+pub struct Onb {
+    axis: [Vec3; 3],
+}
+
+impl Onb {
+    /// Build an orthonormal basis whose `w` axis is the given vector.
+    pub fn from_w(n: &Vec3) -> Onb {
+        let w = unit_vector(n);
+        let a = if w.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = unit_vector(&cross(&w, &a));
+        let u = cross(&w, &v);
+
+        Onb { axis: [u, v, w] }
+    }
+
+    pub fn u(&self) -> &Vec3 {
+        &self.axis[0]
+    }
+
+    pub fn v(&self) -> &Vec3 {
+        &self.axis[1]
+    }
+
+    pub fn w(&self) -> &Vec3 {
+        &self.axis[2]
+    }
+
+    /// Transform a vector expressed in this basis into world space.
+    pub fn local(&self, a: &Vec3) -> Vec3 {
+        a.x() * self.u() + a.y() * self.v() + a.z() * self.w()
+    }
+}
@@ -0,0 +1,190 @@
+//! Probability density functions over directions, used to importance sample scattered rays.
+use std::f32;
+
+use crate::hitable::Hitable;
+use crate::onb::Onb;
+use crate::sampler::Sampler;
+use crate::vec::{dot, unit_vector, Vec3};
+use crate::{random_in_unit_sphere, random_unit_vector};
+
+pub trait Pdf {
+    /// Return the density, with respect to solid angle, of generating `direction`.
+    fn value(&self, direction: &Vec3) -> f32;
+
+    /// Generate a random direction following this distribution.
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3;
+}
+
+/// Cosine-weighted distribution of directions around a normal.
+#[derive(Debug, Clone)]
+pub struct CosinePdf {
+    uvw: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(w: &Vec3) -> CosinePdf {
+        CosinePdf {
+            uvw: Onb::from_w(w),
+        }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: &Vec3) -> f32 {
+        let cosine = dot(&unit_vector(direction), self.uvw.w());
+        if cosine > 0.0 {
+            cosine / f32::consts::PI
+        } else {
+            0.0
+        }
+    }
+
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        self.uvw.local(&random_cosine_direction(sampler))
+    }
+}
+
+/// Distribution of the directions from a point towards an object at some time, as sampled by the
+/// object itself.
+pub struct HitablePdf<'a> {
+    o: Vec3,
+    time: f32,
+    hitable: &'a dyn Hitable,
+}
+
+impl<'a> HitablePdf<'a> {
+    pub fn new(hitable: &'a dyn Hitable, o: &Vec3, time: f32) -> HitablePdf<'a> {
+        HitablePdf {
+            o: *o,
+            time,
+            hitable,
+        }
+    }
+}
+
+impl Pdf for HitablePdf<'_> {
+    fn value(&self, direction: &Vec3) -> f32 {
+        self.hitable.pdf_value(&self.o, direction, self.time)
+    }
+
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        self.hitable.random(&self.o, self.time, sampler)
+    }
+}
+
+/// Distribution of the directions obtained by offsetting a (unit) reflected direction by a random
+/// point in a sphere of radius `fuzz`, as done by fuzzy metals.
+#[derive(Debug, Clone)]
+pub struct FuzzyReflectionPdf {
+    reflected: Vec3,
+    fuzz: f32,
+}
+
+impl FuzzyReflectionPdf {
+    pub fn new(reflected: &Vec3, fuzz: f32) -> FuzzyReflectionPdf {
+        FuzzyReflectionPdf {
+            reflected: unit_vector(reflected),
+            fuzz,
+        }
+    }
+}
+
+impl Pdf for FuzzyReflectionPdf {
+    fn value(&self, direction: &Vec3) -> f32 {
+        // The offset point is uniformly distributed in the ball, so the density of a direction is
+        // the volume of the ball along that direction: integrate t^2 over the chord [t0, t1]
+        // where the ray from the origin crosses the ball.
+        let c = dot(&unit_vector(direction), &self.reflected);
+        let discriminant = c * c - 1.0 + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let t0 = f32::max(c - f32::sqrt(discriminant), 0.0);
+        let t1 = c + f32::sqrt(discriminant);
+        if t1 <= 0.0 {
+            return 0.0;
+        }
+
+        (t1 * t1 * t1 - t0 * t0 * t0) / (4.0 * f32::consts::PI * self.fuzz.powi(3))
+    }
+
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        self.reflected + self.fuzz * random_in_unit_sphere(sampler)
+    }
+}
+
+/// Uniform distribution over all directions.
+#[derive(Debug, Clone, Default)]
+pub struct UniformSpherePdf;
+
+impl Pdf for UniformSpherePdf {
+    fn value(&self, _direction: &Vec3) -> f32 {
+        1.0 / (4.0 * f32::consts::PI)
+    }
+
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        random_unit_vector(sampler)
+    }
+}
+
+/// Henyey-Greenstein distribution of directions around the direction `w` in which light was
+/// travelling. Positive values of the anisotropy `g` favour forward scattering, negative values
+/// backward scattering, and 0 gives a uniform distribution.
+#[derive(Debug, Clone)]
+pub struct HenyeyGreensteinPdf {
+    uvw: Onb,
+    g: f32,
+}
+
+impl HenyeyGreensteinPdf {
+    pub fn new(w: &Vec3, g: f32) -> HenyeyGreensteinPdf {
+        HenyeyGreensteinPdf {
+            uvw: Onb::from_w(w),
+            g,
+        }
+    }
+}
+
+impl Pdf for HenyeyGreensteinPdf {
+    fn value(&self, direction: &Vec3) -> f32 {
+        henyey_greenstein(dot(&unit_vector(direction), self.uvw.w()), self.g)
+    }
+
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.get_2d();
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * r1
+        } else {
+            let sqr_term = (1.0 - g * g) / (1.0 + g - 2.0 * g * r1);
+            (1.0 + g * g - sqr_term * sqr_term) / (2.0 * g)
+        };
+        let cos_theta = cos_theta.clamp(-1.0, 1.0);
+        let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
+        let phi = 2.0 * f32::consts::PI * r2;
+
+        self.uvw.local(&Vec3::new(
+            sin_theta * f32::cos(phi),
+            sin_theta * f32::sin(phi),
+            cos_theta,
+        ))
+    }
+}
+
+/// Evaluate the Henyey-Greenstein phase function for the cosine of the angle between the
+/// directions of the incoming and scattered light.
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * f32::consts::PI * denom * f32::sqrt(denom))
+}
+
+/// Return a random direction around the z axis with a cosine-weighted distribution.
+pub fn random_cosine_direction(sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.get_2d();
+    let z = f32::sqrt(1.0 - r2);
+    let phi = 2.0 * f32::consts::PI * r1;
+    let x = f32::cos(phi) * f32::sqrt(r2);
+    let y = f32::sin(phi) * f32::sqrt(r2);
+
+    Vec3::new(x, y, z)
+}
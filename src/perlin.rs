@@ -1,15 +1,20 @@
 use std::f32;
 
 use lazy_static::lazy_static;
-use rand::{self, Rng};
+use rand::Rng;
 
+use crate::random::{seeded_rng, RenderRng};
 use crate::vec::{self, Vec3};
 
+/// The noise is part of the scene description rather than of the sampling process, so the tables
+/// are always generated from the same seeds.
+const PERLIN_SEED: u64 = 0x7065_726c_696e;
+
 lazy_static! {
-    static ref RANVEC: [Vec3; 256] = generate();
-    static ref PERM_X: [usize; 256] = generate_perm();
-    static ref PERM_Y: [usize; 256] = generate_perm();
-    static ref PERM_Z: [usize; 256] = generate_perm();
+    static ref RANVEC: [Vec3; 256] = generate(&mut seeded_rng(PERLIN_SEED));
+    static ref PERM_X: [usize; 256] = generate_perm(&mut seeded_rng(PERLIN_SEED + 1));
+    static ref PERM_Y: [usize; 256] = generate_perm(&mut seeded_rng(PERLIN_SEED + 2));
+    static ref PERM_Z: [usize; 256] = generate_perm(&mut seeded_rng(PERLIN_SEED + 3));
 }
 
 pub fn turb(p: &Vec3, depth: u32) -> f32 {
@@ -64,8 +69,7 @@ fn perlin_interp(c: &[[[Vec3; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
     accum
 }
 
-fn permute(p: &mut [usize], n: usize) {
-    let mut rng = rand::thread_rng();
+fn permute(p: &mut [usize], n: usize, rng: &mut RenderRng) {
     for i in (1..n).rev() {
         let target = (rng.gen::<f32>() * (i + 1) as f32) as usize;
         p.swap(i, target);
@@ -73,9 +77,7 @@ fn permute(p: &mut [usize], n: usize) {
 }
 
 #[allow(clippy::needless_range_loop)]
-fn generate() -> [Vec3; 256] {
-    let mut rng = rand::thread_rng();
-
+fn generate(rng: &mut RenderRng) -> [Vec3; 256] {
     let mut p = [Vec3::default(); 256];
     for i in 0..256 {
         p[i] = vec::unit_vector(&Vec3::new(
@@ -88,10 +90,10 @@ fn generate() -> [Vec3; 256] {
 }
 
 #[allow(clippy::needless_range_loop)]
-fn generate_perm() -> [usize; 256] {
+fn generate_perm(rng: &mut RenderRng) -> [usize; 256] {
     let mut p = [0; 256];
     p.iter_mut().enumerate().for_each(|(i, v)| *v = i);
 
-    permute(&mut p[..], 256);
+    permute(&mut p[..], 256, rng);
     p
 }
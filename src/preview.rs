@@ -0,0 +1,139 @@
+//! Live preview of a render in the terminal, for renders running where the images can't be
+//! looked at. The film is shrunk to fit the terminal and drawn with half-block characters, each
+//! showing two pixels in 24-bit colour, above a progress bar.
+use std::io::{self, Stdout, Write};
+
+use crossterm::cursor::MoveToPreviousLine;
+use crossterm::queue;
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType};
+
+use crate::film::Film;
+use crate::render::Progress;
+use crate::tonemap::OutputTransform;
+use crate::vec::Vec3;
+
+/// Size of the terminal when it can't be found, e.g. when the output is redirected.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// Draws the film and the progress of a render, each time over the previous drawing.
+pub struct Preview {
+    out: Stdout,
+    /// Number of lines drawn last time
+    lines: u16,
+}
+
+impl Preview {
+    pub fn new() -> Preview {
+        Preview {
+            out: io::stdout(),
+            lines: 0,
+        }
+    }
+
+    /// Draw the film, written with `transform`, and the progress of the render.
+    pub fn draw(
+        &mut self,
+        film: &Film,
+        transform: &OutputTransform,
+        progress: &Progress,
+    ) -> io::Result<()> {
+        let (columns, rows) = terminal::size().unwrap_or(DEFAULT_SIZE);
+        let (width, height) = preview_size(film, columns as usize, rows as usize);
+
+        let out = &mut self.out;
+        if self.lines > 0 {
+            queue!(out, MoveToPreviousLine(self.lines))?;
+        }
+        queue!(out, Clear(ClearType::FromCursorDown))?;
+        let color = |x: usize, y: usize| {
+            let [r, g, b] = transform.apply(&box_filter(film, x, y, width, height));
+            Color::Rgb { r, g, b }
+        };
+        // Each character shows a pixel in its upper half, and the one below in its lower half
+        for y in (0..height).step_by(2) {
+            for x in 0..width {
+                queue!(out, SetForegroundColor(color(x, y)))?;
+                if y + 1 < height {
+                    queue!(out, SetBackgroundColor(color(x, y + 1)))?;
+                }
+                queue!(out, Print('▀'))?;
+            }
+            queue!(out, ResetColor, Print('\n'))?;
+        }
+        queue!(out, Print(progress_bar(progress, width)), Print('\n'))?;
+        out.flush()?;
+
+        self.lines = (height as u16).div_ceil(2) + 1;
+        Ok(())
+    }
+}
+
+/// Return the size in pixels of the preview of `film` in a terminal of the given size, keeping
+/// the film's aspect ratio. Pixels are square, as characters are about twice as tall as wide.
+fn preview_size(film: &Film, columns: usize, rows: usize) -> (usize, usize) {
+    let aspect = film.width() as f32 / film.height() as f32;
+    // Leave a line for the progress bar, and one for the cursor
+    let max_height = 2 * rows.saturating_sub(2).max(1);
+    let mut width = usize::min(columns, film.width()).max(1);
+    let mut height = (width as f32 / aspect).round() as usize;
+    if height > max_height {
+        height = max_height;
+        width = ((height as f32 * aspect).round() as usize).max(1);
+    }
+
+    (width, height.clamp(1, film.height()))
+}
+
+/// Return the average of the pixels of `film` covered by pixel `(x, y)` of a `width` x `height`
+/// preview.
+fn box_filter(film: &Film, x: usize, y: usize, width: usize, height: usize) -> Vec3 {
+    let x0 = x * film.width() / width;
+    let x1 = usize::max((x + 1) * film.width() / width, x0 + 1);
+    let y0 = y * film.height() / height;
+    let y1 = usize::max((y + 1) * film.height() / height, y0 + 1);
+    let mut sum = Vec3::default();
+    for fy in y0..y1 {
+        for fx in x0..x1 {
+            sum += film.pixel(fx, fy);
+        }
+    }
+
+    sum / ((x1 - x0) * (y1 - y0)) as f32
+}
+
+/// Return a line showing the progress of a render, at most `width` characters wide unless that
+/// is too narrow for the numbers.
+fn progress_bar(progress: &Progress, width: usize) -> String {
+    let seconds = progress.elapsed.as_secs();
+    let text = format!(
+        " {:3.0}%  {}rays/s  pass {}  {}:{:02}",
+        100.0 * progress.fraction,
+        si_prefix(progress.rays_per_second),
+        progress.passes,
+        seconds / 60,
+        seconds % 60
+    );
+    let bar_width = width.saturating_sub(text.chars().count()).max(10);
+    let done = ((progress.fraction * bar_width as f32).round() as usize).min(bar_width);
+
+    format!(
+        "{}{}{}",
+        "█".repeat(done),
+        "░".repeat(bar_width - done),
+        text
+    )
+}
+
+/// Format a rate with an SI prefix, e.g. "3.14 M".
+fn si_prefix(value: f64) -> String {
+    let prefixes = ["", "k", "M", "G", "T"];
+    let mut value = value;
+    let mut index = 0;
+    while value >= 1000.0 && index + 1 < prefixes.len() {
+        value /= 1000.0;
+        index += 1;
+    }
+
+    format!("{:.2} {}", value, prefixes[index])
+}
@@ -0,0 +1,51 @@
+//! Random number generation. Every random decision made while building or rendering a scene
+//! goes through a `RenderRng` derived from a user-provided seed, so that the same scene and seed
+//! always produce the same image, regardless of the number of threads or the order in which
+//! tiles get rendered.
+use rand_pcg::Pcg32;
+
+use crate::ray::Ray;
+
+/// The random number generator used throughout the renderer.
+pub type RenderRng = Pcg32;
+
+/// Create a generator from a single seed, e.g. to build a scene.
+pub fn seeded_rng(seed: u64) -> RenderRng {
+    Pcg32::new(mix_bits(seed), 0)
+}
+
+/// Create the generator used for sample number `index` of pixel `(x, y)`. Each pixel gets its own
+/// stream, and each sample its own state within that stream, so a sample's values don't depend
+/// on which thread renders it or on how many samples were taken before it.
+pub fn sample_rng(seed: u64, x: usize, y: usize, index: usize) -> RenderRng {
+    let stream = ((y as u64) << 32) | x as u64;
+    Pcg32::new(sample_seed(seed, x, y, index), stream)
+}
+
+/// Return a seed for sample number `index` of pixel `(x, y)`, derived from the render's seed.
+pub fn sample_seed(seed: u64, x: usize, y: usize, index: usize) -> u64 {
+    let stream = ((y as u64) << 32) | x as u64;
+    mix_bits(seed ^ mix_bits(stream ^ mix_bits(index as u64)))
+}
+
+/// Create a generator from the seed, origin, direction and time of a ray, and from `salt`, which
+/// tells apart the objects hit by the same ray. This is used by objects which make random
+/// decisions in `Hitable::hit`, where no sampler is available, while keeping renders
+/// deterministic.
+pub fn ray_rng(r: &Ray, salt: u64) -> RenderRng {
+    let mut hash = mix_bits(r.seed() ^ mix_bits(salt ^ u64::from(r.time().to_bits())));
+    for i in 0..3 {
+        hash = mix_bits(hash ^ u64::from(r.origin()[i].to_bits()));
+        hash = mix_bits(hash ^ u64::from(r.direction()[i].to_bits()));
+    }
+
+    Pcg32::new(hash, 0)
+}
+
+/// SplitMix64 finalizer, used to turn similar seeds into very different generator states.
+pub fn mix_bits(v: u64) -> u64 {
+    let mut z = v.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -5,6 +5,8 @@ pub struct Ray {
     a: Vec3,
     b: Vec3,
     time: f32,
+    /// Seed of the random decisions made by objects hit along the path of the ray
+    seed: u64,
 }
 
 impl Ray {
@@ -13,11 +15,34 @@ impl Ray {
             a: *a,
             b: *b,
             time: 0.0,
+            seed: 0,
         }
     }
 
     pub fn with_time(a: &Vec3, b: &Vec3, time: f32) -> Ray {
-        Ray { a: *a, b: *b, time }
+        Ray {
+            a: *a,
+            b: *b,
+            time,
+            seed: 0,
+        }
+    }
+
+    /// Return this ray with the given seed, e.g. one derived from the camera sample it is traced
+    /// for.
+    pub fn with_seed(self, seed: u64) -> Ray {
+        Ray { seed, ..self }
+    }
+
+    /// Return the ray continuing the path of this one from `a` along `b`, at the same time and
+    /// with the same seed.
+    pub fn spawn(&self, a: &Vec3, b: &Vec3) -> Ray {
+        Ray {
+            a: *a,
+            b: *b,
+            time: self.time,
+            seed: self.seed,
+        }
     }
 
     pub fn origin(&self) -> &Vec3 {
@@ -32,6 +57,10 @@ impl Ray {
         self.time
     }
 
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
     pub fn point_at_parameter(&self, t: f32) -> Vec3 {
         self.a + t * self.b
     }
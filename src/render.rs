@@ -0,0 +1,1136 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::{ControlFlow, Range};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::aov::Aov;
+use crate::camera::Camera;
+use crate::checkpoint::{self, Checkpoint};
+use crate::denoise::GUIDE_AOVS;
+use crate::distributed::Coordinator;
+use crate::film::{Film, FilmTile};
+use crate::filter::{Filter, FilterKind};
+use crate::hitable::HitRecord;
+use crate::integrator::{Integrator, IntegratorKind, PathDepth};
+use crate::random::sample_seed;
+use crate::sampler::SamplerKind;
+use crate::scene::{self, Scene};
+use crate::vec::Vec3;
+
+/// Parameters controlling how an image is rendered.
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    /// Width of the image, in pixels
+    pub width: usize,
+    /// Height of the image, in pixels
+    pub height: usize,
+    /// Number of samples per pixel. With adaptive sampling, this is the average number of samples
+    /// per pixel which the render may use. The render may stop earlier because of `time_limit`
+    /// or `noise_target`
+    pub samples: usize,
+    /// Size of the (square) tiles the image is split into
+    pub tile_size: usize,
+    /// Seed for all the random decisions made while rendering
+    pub seed: u64,
+    /// Strategy used to generate the samples
+    pub sampler: SamplerKind,
+    /// Algorithm used to compute the radiance along each camera ray
+    pub integrator: IntegratorKind,
+    /// Number of bounces along each path
+    pub depth: PathDepth,
+    /// Filter used to reconstruct the pixels from the samples around them
+    pub filter: FilterKind,
+    /// Radius of the filter, in pixels
+    pub filter_radius: f32,
+    /// Extra buffers to render along with the image
+    pub aovs: Vec<Aov>,
+    /// Whether to denoise the image after rendering. This also renders the AOVs guiding the
+    /// denoiser, if they aren't already requested.
+    pub denoise: bool,
+    /// How aggressively noise gets smoothed out, 1 being a good default
+    pub denoise_strength: f32,
+    /// Whether to spend more samples on noisy pixels than on converged ones, and how
+    pub adaptive: Option<AdaptiveSampling>,
+    /// Number of samples added to each pixel by every pass over the image
+    pub pass_samples: usize,
+    /// File the state of the render is saved to after every pass, so that it can be resumed
+    pub checkpoint: Option<PathBuf>,
+    /// Identifies the scene being rendered, e.g. by a hash of its description, so that
+    /// checkpoints and workers of another scene are rejected
+    pub scene_id: u64,
+    /// Time after which the render stops. Passes are shortened so that the last one finishes
+    /// within the limit, except for the first one
+    pub time_limit: Option<Duration>,
+    /// Mean relative error of the pixels below which the render stops
+    pub noise_target: Option<f32>,
+    /// Part of the image to render. Rays are still computed for the full image, and the pixels
+    /// around the window within the filter's radius are rendered too, so that the window looks
+    /// the same as in a full render
+    pub crop: Option<CropWindow>,
+    /// Whether a cropped render gives a film of the full image, with nothing outside the window,
+    /// rather than just the window
+    pub crop_full_frame: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            width: 400,
+            height: 200,
+            samples: 100,
+            tile_size: 16,
+            seed: 0,
+            sampler: SamplerKind::default(),
+            integrator: IntegratorKind::default(),
+            depth: PathDepth::default(),
+            filter: FilterKind::default(),
+            filter_radius: 0.5,
+            aovs: Vec::new(),
+            denoise: false,
+            denoise_strength: 1.0,
+            adaptive: None,
+            pass_samples: 16,
+            checkpoint: None,
+            scene_id: 0,
+            time_limit: None,
+            noise_target: None,
+            crop: None,
+            crop_full_frame: false,
+        }
+    }
+}
+
+/// Parameters of adaptive sampling. Every pixel first gets `min_samples` samples, then each pass
+/// adds samples to the pixels whose relative error is still above `threshold`, until they
+/// converge, reach their maximum, or the render's total budget is used up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    /// Number of samples taken in every pixel before estimating its error
+    pub min_samples: usize,
+    /// Largest number of samples a pixel can get, as a multiple of the average number of samples
+    pub max_factor: usize,
+    /// Standard error of a pixel's mean luminance, relative to that mean, below which the pixel
+    /// is considered converged
+    pub threshold: f32,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> AdaptiveSampling {
+        AdaptiveSampling {
+            min_samples: 64,
+            max_factor: 8,
+            threshold: 0.01,
+        }
+    }
+}
+
+/// Luminance below which pixels are treated as equally dark when computing their relative error,
+/// so that the noise of nearly black pixels, which can't be seen, doesn't use up the budget.
+const MIN_LUMINANCE: f32 = 0.01;
+
+/// Return the standard error of the mean luminance of the given pixel, relative to that mean.
+fn relative_error(film: &Film, x: usize, y: usize) -> f32 {
+    f32::sqrt(film.variance(x, y)) / f32::max(film.mean_luminance(x, y), MIN_LUMINANCE)
+}
+
+/// Return the relative error of the film's pixels, averaged over the image.
+fn mean_relative_error(film: &Film) -> f32 {
+    let (width, height) = (film.width(), film.height());
+    let total: f64 = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| relative_error(film, x, y) as f64)
+        .sum();
+    (total / (width * height).max(1) as f64) as f32
+}
+
+impl AdaptiveSampling {
+    /// Whether the given pixel of the film needs more samples.
+    fn needs_samples(&self, film: &Film, x: usize, y: usize) -> bool {
+        relative_error(film, x, y) > self.threshold
+    }
+}
+
+/// A region of the image to render, leaving out the rest. It is given as `[x0, y0, x1, y1]`, in
+/// pixels if those are integers and in fractions of the image otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(from = "CropBounds")]
+pub enum CropWindow {
+    /// Region in pixels
+    Pixels(Tile),
+    /// Region in fractions of the image's width and height, from its top-left corner
+    Normalized { x0: f32, y0: f32, x1: f32, y1: f32 },
+}
+
+/// The bounds of a crop window, as given in a scene file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CropBounds {
+    Pixels([usize; 4]),
+    Normalized([f32; 4]),
+}
+
+impl From<CropBounds> for CropWindow {
+    fn from(bounds: CropBounds) -> CropWindow {
+        match bounds {
+            CropBounds::Pixels([x0, y0, x1, y1]) => CropWindow::Pixels(Tile { x0, y0, x1, y1 }),
+            CropBounds::Normalized([x0, y0, x1, y1]) => CropWindow::Normalized { x0, y0, x1, y1 },
+        }
+    }
+}
+
+impl FromStr for CropWindow {
+    type Err = String;
+
+    /// Parse bounds given as `x0,y0,x1,y1`.
+    fn from_str(bounds: &str) -> Result<CropWindow, String> {
+        let invalid = || format!("invalid crop window `{}`", bounds);
+        let values: Vec<&str> = bounds.split(',').map(str::trim).collect();
+        if values.len() != 4 {
+            return Err(invalid());
+        }
+        if let Ok(pixels) = values
+            .iter()
+            .map(|v| v.parse())
+            .collect::<Result<Vec<_>, _>>()
+        {
+            return Ok(CropBounds::Pixels([pixels[0], pixels[1], pixels[2], pixels[3]]).into());
+        }
+        let fractions = values
+            .iter()
+            .map(|v| v.parse())
+            .collect::<Result<Vec<_>, _>>();
+        let f = fractions.map_err(|_| invalid())?;
+        Ok(CropBounds::Normalized([f[0], f[1], f[2], f[3]]).into())
+    }
+}
+
+impl CropWindow {
+    /// Check that the window isn't empty, and that fractions of the image are within it.
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            CropWindow::Pixels(tile) => tile.x0 < tile.x1 && tile.y0 < tile.y1,
+            CropWindow::Normalized { x0, y0, x1, y1 } => {
+                (0.0..1.0).contains(&x0)
+                    && x0 < x1
+                    && x1 <= 1.0
+                    && (0.0..1.0).contains(&y0)
+                    && y0 < y1
+                    && y1 <= 1.0
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("the crop window is empty".to_string())
+        }
+    }
+
+    /// Return the pixels covered by the window in an image of the given size. Pixels partially
+    /// covered by a normalized window are included.
+    pub fn bounds(&self, width: usize, height: usize) -> Tile {
+        let tile = match *self {
+            CropWindow::Pixels(tile) => tile,
+            CropWindow::Normalized { x0, y0, x1, y1 } => Tile {
+                x0: f32::floor(x0 * width as f32) as usize,
+                y0: f32::floor(y0 * height as f32) as usize,
+                x1: f32::ceil(x1 * width as f32) as usize,
+                y1: f32::ceil(y1 * height as f32) as usize,
+            },
+        };
+        let x1 = usize::min(tile.x1, width);
+        let y1 = usize::min(tile.y1, height);
+        Tile {
+            x0: usize::min(tile.x0, x1),
+            y0: usize::min(tile.y0, y1),
+            x1,
+            y1,
+        }
+    }
+}
+
+/// A rectangular region of the image, in pixel coordinates. `x1` and `y1` are exclusive, and `y`
+/// goes from the top of the image to the bottom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+}
+
+/// Split an image of the given size into tiles of at most `tile_size` x `tile_size` pixels.
+pub fn tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let mut tiles = Vec::new();
+    for y0 in (0..height).step_by(tile_size) {
+        for x0 in (0..width).step_by(tile_size) {
+            tiles.push(Tile {
+                x0,
+                y0,
+                x1: usize::min(x0 + tile_size, width),
+                y1: usize::min(y0 + tile_size, height),
+            });
+        }
+    }
+    tiles
+}
+
+/// How far a progressive render has got, as given to its callback after each pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Number of passes rendered so far, including those of the checkpoint it resumed from
+    pub passes: usize,
+    /// Time since the render started
+    pub elapsed: Duration,
+    /// Estimated fraction of the render done, from the samples taken and the time limit. A
+    /// render with a noise target may finish earlier
+    pub fraction: f32,
+    /// Number of rays traced per second by the passes rendered so far
+    pub rays_per_second: f64,
+}
+
+/// Render the given scene using all available cores.
+///
+/// The image is rendered in passes, each adding a few samples to every pixel. Within a pass, the
+/// image is split into tiles which are handed out to rayon's work-stealing thread pool, so
+/// expensive tiles don't hold up the rest of the render. Each tile splats its samples into its own
+/// `FilmTile`, and finished tiles are then added to the returned `Film` in a fixed order, so the
+/// result doesn't depend on scheduling. With adaptive sampling, each pass only samples the pixels
+/// which haven't converged yet.
+pub fn render(scene: &Scene, camera: &Camera, settings: &RenderSettings) -> Film {
+    render_progressive(scene, camera, settings, None, |_, _| {
+        ControlFlow::Continue(())
+    })
+}
+
+/// Render the given scene like `render`, calling `on_pass` with the film and the progress of the
+/// render after each pass. The render stops early if `on_pass` returns `Break`.
+///
+/// If `resume` is given, the render continues from that checkpoint, adding samples until
+/// `settings.samples` is reached. Since samples are determined by their index within their pixel,
+/// this gives the same image as a render which was never interrupted.
+///
+/// With a crop window, the film only covers the window, unless `settings.crop_full_frame` is set.
+pub fn render_progressive<F>(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &RenderSettings,
+    resume: Option<Checkpoint>,
+    on_pass: F,
+) -> Film
+where
+    F: FnMut(&Film, &Progress) -> ControlFlow<()>,
+{
+    let renderer = Renderer::new(scene, camera, settings);
+    render_passes(&renderer, resume, None, on_pass)
+}
+
+/// Render the given scene like `render_progressive`, handing the tiles of each pass out to the
+/// workers of `coordinator`. The workers must render the same scene with the same settings.
+pub fn render_distributed<F>(
+    renderer: &Renderer,
+    resume: Option<Checkpoint>,
+    coordinator: &mut Coordinator,
+    on_pass: F,
+) -> Film
+where
+    F: FnMut(&Film, &Progress) -> ControlFlow<()>,
+{
+    render_passes(renderer, resume, Some(coordinator), on_pass)
+}
+
+fn render_passes<F>(
+    renderer: &Renderer,
+    resume: Option<Checkpoint>,
+    mut coordinator: Option<&mut Coordinator>,
+    mut on_pass: F,
+) -> Film
+where
+    F: FnMut(&Film, &Progress) -> ControlFlow<()>,
+{
+    let start = Instant::now();
+    let settings = renderer.settings;
+    // The film only covers the crop window and the pixels around it while rendering
+    let (mut film, mut passes) = match resume {
+        Some(checkpoint) if checkpoint.fingerprint == renderer.fingerprint() => {
+            info!(
+                "Resuming render after {} passes ({:.1} samples per pixel)",
+                checkpoint.passes,
+                checkpoint.film.mean_sample_count()
+            );
+            (checkpoint.film, checkpoint.passes)
+        }
+        Some(_) => {
+            warn!("Checkpoint is of another scene or other settings, starting from scratch");
+            (renderer.new_film(), 0)
+        }
+        None => (renderer.new_film(), 0),
+    };
+    info!(
+        "Rendering {}x{} image ({} tiles, {})...",
+        settings.width,
+        settings.height,
+        renderer.tiles.len(),
+        match &coordinator {
+            Some(coordinator) => format!("{} workers", coordinator.worker_count()),
+            None => format!("{} threads", rayon::current_num_threads()),
+        }
+    );
+    if settings.crop.is_some() {
+        info!("Cropped to {:?}", renderer.crop);
+    }
+
+    // Time spent in passes and number of samples taken by this call, to predict how many samples
+    // fit in the time left
+    let mut pass_time = Duration::ZERO;
+    let mut rendered = 0;
+    let mut rays = 0;
+    loop {
+        if let Some(target) = settings.noise_target {
+            let error = mean_relative_error(&film);
+            if film.mean_sample_count() > 0.0 && error <= target {
+                info!("Reached a mean relative error of {:.4}", error);
+                break;
+            }
+        }
+        let mut limit = usize::MAX;
+        if let Some(time_limit) = settings.time_limit {
+            let elapsed = start.elapsed();
+            if elapsed >= time_limit {
+                info!("Reached the time limit of {:.2?}", time_limit);
+                break;
+            }
+            if rendered > 0 {
+                let sample_time = pass_time.as_secs_f64() / rendered as f64;
+                limit = ((time_limit - elapsed).as_secs_f64() / sample_time) as usize;
+            }
+        }
+        let samples = match renderer.next_pass(&film, limit) {
+            Some(samples) => samples,
+            None => break,
+        };
+
+        let pass_start = Instant::now();
+        rays += renderer.render_pass(&mut film, &samples, coordinator.as_deref_mut());
+        pass_time += pass_start.elapsed();
+        rendered += samples.iter().map(|range| range.len()).sum::<usize>();
+        passes += 1;
+        info!(
+            "Pass {} done after {:.2?}, {:.1} samples per pixel",
+            passes,
+            start.elapsed(),
+            film.mean_sample_count()
+        );
+        if let Some(path) = &settings.checkpoint {
+            if let Err(e) = checkpoint::save(path, &film, passes, renderer.fingerprint()) {
+                warn!("Failed to save checkpoint {}: {}", path.display(), e);
+            }
+        }
+        let mut fraction = film.mean_sample_count() / settings.samples as f32;
+        if let Some(time_limit) = settings.time_limit {
+            fraction = fraction.max(start.elapsed().as_secs_f32() / time_limit.as_secs_f32());
+        }
+        let progress = Progress {
+            passes,
+            elapsed: start.elapsed(),
+            fraction: fraction.min(1.0),
+            rays_per_second: rays as f64 / pass_time.as_secs_f64(),
+        };
+        if on_pass(&renderer.output_film(&film), &progress).is_break() {
+            info!("Render stopped");
+            break;
+        }
+    }
+    info!(
+        "Rendering done in {:.2?}: {} passes, {:.1} samples per pixel, mean relative error {:.4}",
+        start.elapsed(),
+        passes,
+        film.mean_sample_count(),
+        mean_relative_error(&film)
+    );
+
+    if settings.denoise {
+        let start = Instant::now();
+        film.denoise(settings.denoise_strength);
+        info!("Denoising done in {:.2?}", start.elapsed());
+    }
+
+    match renderer.output_film(&film) {
+        Cow::Owned(output) => output,
+        Cow::Borrowed(_) => film,
+    }
+}
+
+/// Return the largest number of samples a pixel can get.
+fn max_samples(settings: &RenderSettings) -> usize {
+    match &settings.adaptive {
+        Some(adaptive) => settings.samples.saturating_mul(adaptive.max_factor.max(1)),
+        None => settings.samples,
+    }
+}
+
+/// Return the mask of the pixels within `radius` pixels (along each axis) of a pixel set in
+/// `mask`.
+fn dilate(mask: &[bool], width: usize, height: usize, radius: usize) -> Vec<bool> {
+    let dilate_rows: Vec<bool> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let x0 = x.saturating_sub(radius);
+            let x1 = usize::min(x + radius + 1, width);
+            mask[y * width + x0..y * width + x1].iter().any(|&m| m)
+        })
+        .collect();
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let y0 = y.saturating_sub(radius);
+            let y1 = usize::min(y + radius + 1, height);
+            (y0..y1).any(|y| dilate_rows[y * width + x])
+        })
+        .collect()
+}
+
+/// The samples to take in the pixels of a tile during a pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileJob {
+    /// The pixels to sample, in film coordinates
+    pub tile: Tile,
+    /// The indices of the samples to take in each pixel of the tile, row by row
+    pub samples: Vec<Range<usize>>,
+}
+
+/// Everything needed to render passes over the image.
+pub struct Renderer<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    settings: &'a RenderSettings,
+    integrator: Box<dyn Integrator>,
+    filter: Box<dyn Filter>,
+    /// The buffers stored in the film along with the image
+    aovs: Vec<Aov>,
+    /// The region of the image covered by the film
+    window: Tile,
+    /// The region of the image output by a cropped render, within `window`
+    crop: Option<Tile>,
+    /// The tiles of the film
+    tiles: Vec<Tile>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(scene: &'a Scene, camera: &'a Camera, settings: &'a RenderSettings) -> Renderer<'a> {
+        let mut aovs = settings.aovs.clone();
+        if settings.denoise {
+            for aov in GUIDE_AOVS {
+                if !aovs.contains(&aov) {
+                    aovs.push(aov);
+                }
+            }
+        }
+        let (width, height) = (settings.width, settings.height);
+        let crop = settings.crop.map(|crop| crop.bounds(width, height));
+        let window = match &crop {
+            Some(crop) => FilmTile::padded_bounds(crop, settings.filter_radius, width, height),
+            None => Tile {
+                x0: 0,
+                y0: 0,
+                x1: width,
+                y1: height,
+            },
+        };
+
+        Renderer {
+            scene,
+            camera,
+            settings,
+            integrator: settings.integrator.create(settings.depth),
+            filter: settings.filter.create(settings.filter_radius),
+            aovs,
+            window,
+            crop,
+            tiles: tiles(window.width(), window.height(), settings.tile_size),
+        }
+    }
+
+    /// The buffers stored in the film along with the image.
+    pub fn aovs(&self) -> &[Aov] {
+        &self.aovs
+    }
+
+    /// Return a hash of the settings which determine the samples of each pixel, so that
+    /// processes rendering parts of the same image, and renders resumed from checkpoints, can
+    /// check that they agree. The scene is only covered through `scene_id`.
+    ///
+    /// The number of samples is left out, so that a render can be resumed with more samples,
+    /// except for the stratified sampler whose strata depend on it.
+    pub fn fingerprint(&self) -> u64 {
+        let s = self.settings;
+        let strata = (s.sampler == SamplerKind::Stratified).then(|| max_samples(s));
+        let adaptive = s.adaptive.map(|a| (a.min_samples, a.threshold));
+        let key = format!(
+            "{:?}",
+            (
+                (s.width, s.height, strata, s.seed, s.sampler, s.integrator),
+                (s.depth, s.filter, s.filter_radius, adaptive, s.scene_id),
+                (&self.aovs, self.window, &self.tiles),
+            )
+        );
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Return the bounds of the film tile which the samples taken in `tile` are splatted into.
+    pub fn film_tile_bounds(&self, tile: &Tile) -> Tile {
+        let (width, height) = (self.window.width(), self.window.height());
+        FilmTile::padded_bounds(tile, self.filter.radius(), width, height)
+    }
+
+    /// Return the film which the render outputs, given the film covering the rendered window.
+    fn output_film<'f>(&self, film: &'f Film) -> Cow<'f, Film> {
+        let crop = match &self.crop {
+            Some(crop) => crop,
+            None => return Cow::Borrowed(film),
+        };
+        let window = &self.window;
+        let cropped = film.crop(&Tile {
+            x0: crop.x0 - window.x0,
+            y0: crop.y0 - window.y0,
+            x1: crop.x1 - window.x0,
+            y1: crop.y1 - window.y0,
+        });
+        let settings = self.settings;
+        if settings.crop_full_frame {
+            Cow::Owned(cropped.expand(settings.width, settings.height, crop.x0, crop.y0))
+        } else {
+            Cow::Owned(cropped)
+        }
+    }
+
+    /// Create an empty film covering the rendered window.
+    fn new_film(&self) -> Film {
+        Film::new(self.window.width(), self.window.height(), &self.aovs)
+    }
+
+    /// Take samples in the pixels of `film` and add them to it, and return the number of rays
+    /// traced. `samples` holds the indices of the samples to take in each pixel, row by row. The
+    /// tiles are rendered by the workers of `coordinator` if given, and otherwise on the thread
+    /// pool.
+    fn render_pass(
+        &self,
+        film: &mut Film,
+        samples: &[Range<usize>],
+        coordinator: Option<&mut Coordinator>,
+    ) -> u64 {
+        let width = film.width();
+        let jobs: Vec<TileJob> = self
+            .tiles
+            .iter()
+            .map(|tile| TileJob {
+                tile: *tile,
+                samples: (tile.y0..tile.y1)
+                    .flat_map(|y| (tile.x0..tile.x1).map(move |x| samples[y * width + x].clone()))
+                    .collect(),
+            })
+            .filter(|job| job.samples.iter().any(|range| !range.is_empty()))
+            .collect();
+
+        let mut film_tiles = match coordinator {
+            Some(coordinator) => coordinator.render(&jobs, self),
+            None => vec![None; jobs.len()],
+        };
+        // Tiles which no worker could render are rendered here
+        film_tiles
+            .par_iter_mut()
+            .zip(&jobs)
+            .filter(|(film_tile, _)| film_tile.is_none())
+            .for_each(|(film_tile, job)| *film_tile = Some(self.render_job(job)));
+
+        for film_tile in film_tiles.iter().flatten() {
+            film.add_tile(film_tile);
+        }
+        film_tiles.iter().flatten().map(FilmTile::rays).sum()
+    }
+
+    /// Render the samples of a job, and return the film tile they were splatted into.
+    pub fn render_job(&self, job: &TileJob) -> FilmTile {
+        let tile = &job.tile;
+        let mut film_tile = FilmTile::new(self.film_tile_bounds(tile), &self.aovs);
+        self.render_tile(tile, &mut film_tile, &|x, y| {
+            job.samples[(y - tile.y0) * tile.width() + (x - tile.x0)].clone()
+        });
+        film_tile
+    }
+
+    /// Return the indices of the samples to take in each pixel during the next pass, row by
+    /// row, or `None` if the render is complete. The pass takes at most `limit` samples in total,
+    /// except for the first samples of an adaptive render.
+    fn next_pass(&self, film: &Film, limit: usize) -> Option<Vec<Range<usize>>> {
+        let settings = self.settings;
+        let counts: Vec<usize> = (0..film.height())
+            .flat_map(|y| (0..film.width()).map(move |x| (x, y)))
+            .map(|(x, y)| film.sample_count(x, y) as usize)
+            .collect();
+        let samples = match &settings.adaptive {
+            None => {
+                let pass_samples =
+                    usize::min(settings.pass_samples.max(1), limit / counts.len().max(1));
+                let end = |count| usize::min(count + pass_samples, settings.samples);
+                counts
+                    .iter()
+                    .map(|&count| count..usize::max(count, end(count)))
+                    .collect()
+            }
+            Some(adaptive) => self.adaptive_pass(film, &counts, adaptive, limit),
+        };
+
+        if samples.iter().all(|range| range.is_empty()) {
+            None
+        } else {
+            Some(samples)
+        }
+    }
+
+    /// Return the samples to take in each pixel during the next pass of an adaptive render.
+    fn adaptive_pass(
+        &self,
+        film: &Film,
+        counts: &[usize],
+        adaptive: &AdaptiveSampling,
+        limit: usize,
+    ) -> Vec<Range<usize>> {
+        let settings = self.settings;
+        let (width, height) = (film.width(), film.height());
+        let max_samples = max_samples(settings);
+        let min_samples = usize::min(adaptive.min_samples.max(1), max_samples);
+        if counts.iter().any(|&count| count < min_samples) {
+            return counts
+                .iter()
+                .map(|&count| count..usize::max(count, min_samples))
+                .collect();
+        }
+
+        let noisy: Vec<bool> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| adaptive.needs_samples(film, x, y))
+            .collect();
+        // Samples are splatted to the pixels around them, so all the pixels within the filter's
+        // radius of a noisy one get sampled as densely. Otherwise the samples of the noisy pixels
+        // would outweigh those of converged neighbours.
+        let radius = f32::ceil(self.filter.radius()) as usize;
+        let active: Vec<bool> = dilate(&noisy, width, height, radius)
+            .into_iter()
+            .zip(counts)
+            .map(|(noisy, &count)| noisy && count < max_samples)
+            .collect();
+        let active_count = active.iter().filter(|&&a| a).count();
+        debug!("{} pixels left to sample", active_count);
+
+        // Spread what is left of the budget over the active pixels
+        let budget = settings.samples.saturating_mul(width * height);
+        let taken: usize = counts.iter().sum();
+        let available = usize::min(budget.saturating_sub(taken), limit);
+        let pass_samples = usize::min(
+            settings.pass_samples.max(1),
+            available / active_count.max(1),
+        );
+        counts
+            .iter()
+            .zip(&active)
+            .map(|(&count, &active)| {
+                if active {
+                    count..usize::min(count + pass_samples, max_samples)
+                } else {
+                    count..count
+                }
+            })
+            .collect()
+    }
+
+    /// Trace the samples of the pixels in `tile`, and splat them into `film_tile`. Pixels are in
+    /// film coordinates, which are offset from the image's by the crop window.
+    fn render_tile<F>(&self, tile: &Tile, film_tile: &mut FilmTile, samples: &F)
+    where
+        F: Fn(usize, usize) -> Range<usize>,
+    {
+        debug!("Rendering tile {:?}", tile);
+        let (scene, camera, settings) = (self.scene, self.camera, self.settings);
+        let nx = settings.width as f32;
+        let ny = settings.height as f32;
+        let mut sampler = settings
+            .sampler
+            .create(settings.seed, max_samples(settings));
+        let aovs = film_tile.aovs().to_vec();
+        let mut aov_values = vec![Vec3::default(); aovs.len()];
+        let mut rec = HitRecord::default();
+        // Drop the rays counted for anything else this thread did
+        scene::take_ray_count();
+        for y in tile.y0..tile.y1 {
+            // The camera's origin is at the bottom of the image
+            let j = settings.height - 1 - (y + self.window.y0);
+            for x in tile.x0..tile.x1 {
+                let i = x + self.window.x0;
+                for s in samples(x, y) {
+                    sampler.start_sample(i, j, s);
+                    // Offset of the sample within the pixel, from its top-left corner
+                    let (dx, dy) = sampler.get_2d();
+                    let u = (i as f32 + dx) / nx;
+                    let v = (j as f32 + 1.0 - dy) / ny;
+                    let seed = sample_seed(settings.seed, i, j, s);
+                    let ray = camera.get_ray(u, v, &mut *sampler).with_seed(seed);
+                    if !aovs.is_empty() {
+                        let hit = scene.hit(&ray, 0.001, f32::INFINITY, &mut rec);
+                        for (value, aov) in aov_values.iter_mut().zip(&aovs) {
+                            *value = aov.value(&ray, if hit { Some(&rec) } else { None });
+                        }
+                    }
+                    let l = self.integrator.li(&ray, scene, &mut *sampler);
+                    film_tile.add_sample(x as f32 + dx, y as f32 + dy, &l, &*self.filter);
+                    film_tile.add_pixel_sample(x, y, &l, &aov_values);
+                }
+            }
+        }
+        film_tile.add_rays(scene::take_ray_count());
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::environment::GradientEnvironment;
+    use crate::hitable::{Hitable, Sphere};
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::random::seeded_rng;
+    use crate::texture::ConstantTexture;
+
+    /// A small scene of a sphere on the ground next to a light, under the sky.
+    pub fn test_scene(settings: &RenderSettings) -> (Scene, Camera) {
+        let white = Arc::new(Lambertian::constant(Vec3::new(0.7, 0.7, 0.7)));
+        let light = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
+            Vec3::new(4.0, 4.0, 4.0),
+        ))));
+        let objects: Vec<Arc<dyn Hitable>> = vec![
+            Arc::new(Sphere::new(
+                Vec3::new(0.0, -100.0, 0.0),
+                100.0,
+                white.clone(),
+            )),
+            Arc::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, white)),
+            Arc::new(Sphere::new(Vec3::new(2.0, 2.5, 1.0), 0.5, light)),
+        ];
+        let aspect = settings.width as f32 / settings.height as f32;
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 8.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            aspect,
+            0.0,
+            0.0,
+            1.0,
+            8.0,
+        );
+        let environment = Arc::new(GradientEnvironment::sky());
+        let scene = Scene::new(
+            objects,
+            environment,
+            0.0,
+            1.0,
+            &mut seeded_rng(settings.seed),
+        );
+        (scene, camera)
+    }
+
+    /// Small render settings, with AOVs and several passes.
+    pub fn test_settings() -> RenderSettings {
+        RenderSettings {
+            width: 24,
+            height: 16,
+            samples: 6,
+            pass_samples: 2,
+            seed: 7,
+            aovs: vec![Aov::Normal, Aov::ObjectId],
+            ..RenderSettings::default()
+        }
+    }
+
+    /// Render with a thread pool of the given size.
+    fn render_with_threads(settings: &RenderSettings, threads: usize) -> Film {
+        let (scene, camera) = test_scene(settings);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| render(&scene, &camera, settings))
+    }
+
+    fn max_difference(film: &Film, other: &Film) -> f32 {
+        let pixels = film.pixels().into_iter().zip(other.pixels());
+        pixels.fold(0.0, |max, (a, b)| f32::max(max, (a - b).length()))
+    }
+
+    #[test]
+    fn render_is_deterministic() {
+        let settings = test_settings();
+        let film = render_with_threads(&settings, 1);
+        for (threads, tile_size) in [(3, 16), (1, 5), (4, 7)] {
+            let other = render_with_threads(
+                &RenderSettings {
+                    tile_size,
+                    ..settings.clone()
+                },
+                threads,
+            );
+            assert_eq!(film.pixels(), other.pixels());
+            for &aov in &settings.aovs {
+                assert_eq!(film.aov_pixels(aov), other.aov_pixels(aov));
+            }
+        }
+    }
+
+    #[test]
+    fn render_with_wide_filter_is_deterministic() {
+        let settings = RenderSettings {
+            filter_radius: 1.5,
+            ..test_settings()
+        };
+        let film = render_with_threads(&settings, 1);
+        assert_eq!(film.pixels(), render_with_threads(&settings, 3).pixels());
+
+        // Pixels near the edges of tiles get samples from several tiles, which are summed in
+        // another order with tiles of another size
+        let other = render_with_threads(
+            &RenderSettings {
+                tile_size: 5,
+                ..settings.clone()
+            },
+            3,
+        );
+        assert!(max_difference(&film, &other) < 1e-5);
+        assert_eq!(
+            film.aov_pixels(Aov::ObjectId),
+            other.aov_pixels(Aov::ObjectId)
+        );
+    }
+
+    #[test]
+    fn object_ids_are_kept_over_passes() {
+        // Pixels get samples from the tiles around them too
+        let settings = RenderSettings {
+            filter_radius: 1.5,
+            ..test_settings()
+        };
+        let one_pass = render_with_threads(
+            &RenderSettings {
+                pass_samples: settings.samples,
+                ..settings.clone()
+            },
+            1,
+        );
+        let passes = render_with_threads(&settings, 1);
+        let ids = passes.aov_pixels(Aov::ObjectId).unwrap();
+        assert_eq!(Some(&ids), one_pass.aov_pixels(Aov::ObjectId).as_ref());
+        // Ids are those of the objects, or 0 where the sky is seen
+        assert!(ids.iter().all(|id| [0.0, 1.0, 2.0, 3.0].contains(&id.r())));
+        assert!(ids.iter().any(|id| id.r() == 2.0));
+    }
+    #[test]
+    fn sample_count_aov_matches_film_under_adaptive_sampling() {
+        let settings = RenderSettings {
+            samples: 16,
+            pass_samples: 4,
+            adaptive: Some(AdaptiveSampling {
+                min_samples: 4,
+                max_factor: 4,
+                threshold: 0.05,
+            }),
+            aovs: vec![Aov::SampleCount],
+            ..test_settings()
+        };
+        let film = render_with_threads(&settings, 2);
+        let counts = film.aov_pixels(Aov::SampleCount).unwrap();
+        for y in 0..film.height() {
+            for x in 0..film.width() {
+                let n = film.sample_count(x, y) as f32;
+                assert_eq!(counts[y * film.width() + x], Vec3::new(n, n, n));
+            }
+        }
+        // Converged pixels got fewer samples than noisy ones, which the heatmap tells apart
+        let max = counts.iter().map(|c| c.r()).fold(0.0, f32::max);
+        let min = counts.iter().map(|c| c.r()).fold(max, f32::min);
+        assert!(min >= 4.0 && min < max, "{} {}", min, max);
+        let colors = [min, max].map(|n| Aov::SampleCount.ldr_color(&Vec3::new(n, n, n), max));
+        assert_ne!(colors[0], colors[1]);
+        assert_eq!(colors[1], [255, 0, 0]);
+    }
+
+    #[test]
+    fn resuming_with_more_samples_gives_the_same_image() {
+        let path = std::env::temp_dir().join(format!("rtiow-{}-resume.ckp", std::process::id()));
+        let settings = RenderSettings {
+            samples: 4,
+            checkpoint: Some(path.clone()),
+            ..test_settings()
+        };
+        render_with_threads(&settings, 2);
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let settings = RenderSettings {
+            samples: 8,
+            checkpoint: None,
+            ..settings
+        };
+        let (scene, camera) = test_scene(&settings);
+        // Only the passes adding samples 4 to 8 are left to render
+        let mut passes = Vec::new();
+        let resumed = render_progressive(&scene, &camera, &settings, Some(checkpoint), |_, p| {
+            passes.push(p.passes);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(passes, [3, 4]);
+        let straight = render_with_threads(&settings, 2);
+        assert_eq!(resumed.pixels(), straight.pixels());
+        for &aov in &settings.aovs {
+            assert_eq!(resumed.aov_pixels(aov), straight.aov_pixels(aov));
+        }
+    }
+
+    #[test]
+    fn render_stops_at_noise_target() {
+        let target = 0.04;
+        let settings = RenderSettings {
+            samples: 256,
+            pass_samples: 4,
+            noise_target: Some(target),
+            ..test_settings()
+        };
+        let (scene, camera) = test_scene(&settings);
+        let mut passes = 0;
+        let film = render_progressive(&scene, &camera, &settings, None, |_, p| {
+            passes = p.passes;
+            ControlFlow::Continue(())
+        });
+        assert!(passes > 1 && passes < 64, "{}", passes);
+        assert!(mean_relative_error(&film) <= target);
+        let n = 4 * passes as u32;
+        for y in 0..film.height() {
+            for x in 0..film.width() {
+                assert_eq!(film.sample_count(x, y), n);
+                let l = film.pixel(x, y);
+                assert!(l.r().is_finite() && l.g().is_finite() && l.b().is_finite());
+            }
+        }
+        assert!(film.aov_pixels(Aov::Normal).is_some());
+    }
+
+    #[test]
+    fn cropped_pixels_match_full_render() {
+        let window = Tile {
+            x0: 5,
+            y0: 3,
+            x1: 17,
+            y1: 11,
+        };
+        // Pixels at the edges of the window get samples from outside it with a wide filter,
+        // which are summed in another order
+        for (filter_radius, tolerance) in [(0.5, 0.0), (2.0, 1e-5)] {
+            let settings = RenderSettings {
+                filter_radius,
+                ..test_settings()
+            };
+            let full = render_with_threads(&settings, 2);
+            let close = |a: Vec3, b: Vec3| (a - b).length() <= tolerance;
+            for crop in [
+                CropWindow::Pixels(window),
+                "0.21,0.1875,0.7,0.6875".parse().unwrap(),
+            ] {
+                assert_eq!(crop.bounds(settings.width, settings.height), window);
+                let settings = RenderSettings {
+                    crop: Some(crop),
+                    ..settings.clone()
+                };
+                let cropped = render_with_threads(&settings, 3);
+                assert_eq!((cropped.width(), cropped.height()), (12, 8));
+                for y in 0..8 {
+                    for x in 0..12 {
+                        let (fx, fy) = (x + window.x0, y + window.y0);
+                        assert!(close(cropped.pixel(x, y), full.pixel(fx, fy)));
+                        assert_eq!(cropped.sample_count(x, y), full.sample_count(fx, fy));
+                    }
+                }
+
+                let full_frame = render_with_threads(
+                    &RenderSettings {
+                        crop_full_frame: true,
+                        ..settings
+                    },
+                    3,
+                );
+                assert_eq!((full_frame.width(), full_frame.height()), (24, 16));
+                for y in 0..16 {
+                    for x in 0..24 {
+                        let inside =
+                            window.x0 <= x && x < window.x1 && window.y0 <= y && y < window.y1;
+                        let expected = if inside {
+                            full.pixel(x, y)
+                        } else {
+                            Vec3::default()
+                        };
+                        assert!(close(full_frame.pixel(x, y), expected));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_crop_windows() {
+        let pixels: CropWindow = "1,2,30,40".parse().unwrap();
+        let tile = Tile {
+            x0: 1,
+            y0: 2,
+            x1: 30,
+            y1: 40,
+        };
+        assert_eq!(pixels, CropWindow::Pixels(tile));
+        let fractions: CropWindow = "0, 0.5, 0.5, 1".parse().unwrap();
+        let normalized = CropWindow::Normalized {
+            x0: 0.0,
+            y0: 0.5,
+            x1: 0.5,
+            y1: 1.0,
+        };
+        assert_eq!(fractions, normalized);
+        assert!("1,2,3".parse::<CropWindow>().is_err());
+        assert!("1,2,3,x".parse::<CropWindow>().is_err());
+        assert!("5,0,5,10"
+            .parse::<CropWindow>()
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!("0,0,1.5,1"
+            .parse::<CropWindow>()
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use lazy_static::lazy_static;
+use rand::Rng;
+
+use crate::random::{mix_bits, sample_rng, seeded_rng, RenderRng};
+use crate::sampler::{permutation_element, pixel_hash, Sampler, ONE_MINUS_EPSILON};
+
+/// Number of dimensions generated from the Halton sequence. Paths using more dimensions than this
+/// get uniform random numbers instead, as the radical inverse in very large bases isn't much
+/// better than random anyway.
+const MAX_HALTON_DIMENSION: usize = 256;
+
+lazy_static! {
+    static ref PRIMES: Vec<u32> = generate_primes(MAX_HALTON_DIMENSION);
+}
+
+/// Sampler based on the Halton sequence: dimension `d` is the radical inverse of the sample index
+/// in the `d`-th prime base. Each pixel uses a different Owen scrambling of the digits so that
+/// neighbouring pixels aren't correlated.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel_hash: u64,
+    index: u64,
+    dim: usize,
+    rng: RenderRng,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            seed,
+            pixel_hash: 0,
+            index: 0,
+            dim: 0,
+            rng: seeded_rng(seed),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel_hash = pixel_hash(self.seed, x, y);
+        self.index = index as u64;
+        self.dim = 0;
+        self.rng = sample_rng(self.seed, x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        if self.dim >= MAX_HALTON_DIMENSION {
+            return self.rng.gen();
+        }
+        let hash = mix_bits(self.pixel_hash ^ mix_bits(self.dim as u64));
+        let value = owen_scrambled_radical_inverse(PRIMES[self.dim], self.index, hash);
+        self.dim += 1;
+
+        value
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let u = self.get_1d();
+        let v = self.get_1d();
+        (u, v)
+    }
+}
+
+/// Compute the radical inverse of `a` in the given base, randomly permuting each digit depending
+/// on the digits that come before it.
+fn owen_scrambled_radical_inverse(base: u32, mut a: u64, hash: u64) -> f32 {
+    let base64 = base as u64;
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_m = 1.0f32;
+    let mut reversed_digits = 0u64;
+    // Keep going past the last non-zero digit of `a` until we run out of precision, as the
+    // scrambled digits generally aren't zero.
+    while 1.0 - (base - 1) as f32 * inv_base_m < 1.0 {
+        let next = a / base64;
+        let digit_value = (a - next * base64) as u32;
+        let digit_hash = mix_bits(hash ^ reversed_digits) as u32;
+        let digit_value = permutation_element(digit_value, base, digit_hash);
+        reversed_digits = reversed_digits * base64 + digit_value as u64;
+        inv_base_m *= inv_base;
+        a = next;
+    }
+
+    f32::min(
+        (reversed_digits as f64 * inv_base_m as f64) as f32,
+        ONE_MINUS_EPSILON,
+    )
+}
+
+fn generate_primes(n: usize) -> Vec<u32> {
+    let mut primes: Vec<u32> = Vec::with_capacity(n);
+    let mut candidate = 2;
+    while primes.len() < n {
+        if primes
+            .iter()
+            .take_while(|&&p| p * p <= candidate)
+            .all(|&p| candidate % p != 0)
+        {
+            primes.push(candidate);
+        }
+        candidate += 1;
+    }
+    primes
+}
@@ -0,0 +1,207 @@
+//! Samplers generate the sample values used for every random decision made while tracing a camera
+//! sample: the position within the pixel, the position on the lens, the time, and every scattering
+//! decision along the path. Each call to `get_1d`/`get_2d` consumes a new dimension of the sample,
+//! which lets low-discrepancy samplers distribute the samples of a pixel evenly in every
+//! dimension.
+mod halton;
+mod sobol;
+mod stratified;
+mod uniform;
+
+pub use self::halton::*;
+pub use self::sobol::*;
+pub use self::stratified::*;
+pub use self::uniform::*;
+
+use serde::Deserialize;
+
+use crate::random::mix_bits;
+
+/// The largest `f32` strictly smaller than 1.
+pub const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+pub trait Sampler {
+    /// Start generating sample number `index` of pixel `(x, y)`. This resets the current
+    /// dimension.
+    fn start_sample(&mut self, x: usize, y: usize, index: usize);
+
+    /// Return the next dimension of the current sample, in `[0, 1)`.
+    fn get_1d(&mut self) -> f32;
+
+    /// Return the next two dimensions of the current sample, in `[0, 1)^2`.
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+/// The different sampling strategies available to the renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerKind {
+    /// Independent uniform random numbers
+    #[default]
+    Uniform,
+    /// Jittered samples, one per stratum of each dimension
+    Stratified,
+    /// Owen-scrambled Halton sequence
+    Halton,
+    /// Owen-scrambled Sobol sequence
+    Sobol,
+}
+
+impl SamplerKind {
+    /// Create a sampler of this kind generating `spp` samples per pixel.
+    pub fn create(&self, seed: u64, spp: usize) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Uniform => Box::new(UniformSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, spp)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+/// Hash a pixel's coordinates together with the render seed.
+fn pixel_hash(seed: u64, x: usize, y: usize) -> u64 {
+    mix_bits(seed ^ mix_bits(((y as u64) << 32) | x as u64))
+}
+
+/// Return the `i`-th element of a random permutation of `[0, l)` determined by `p`, without
+/// having to build the permutation (Kensler, "Correlated Multi-Jittered Sampling").
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [
+        SamplerKind::Uniform,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    /// Return the first `dims` dimensions of samples `0..spp` of a pixel.
+    fn samples(kind: SamplerKind, spp: usize, dims: usize) -> Vec<Vec<f32>> {
+        let mut sampler = kind.create(7, spp);
+        (0..spp)
+            .map(|index| {
+                sampler.start_sample(3, 5, index);
+                (0..dims).map(|_| sampler.get_1d()).collect()
+            })
+            .collect()
+    }
+
+    /// Return the stratum of `[0, 1)` split into `n` strata which contains `value`.
+    fn stratum(value: f32, n: usize) -> usize {
+        (value * n as f32) as usize
+    }
+
+    /// Check that each of the `n` strata of `[0, 1)` holds exactly one of the values.
+    fn assert_stratified(values: impl Iterator<Item = f32>, n: usize) {
+        let mut strata: Vec<usize> = values.map(|value| stratum(value, n)).collect();
+        strata.sort_unstable();
+        assert_eq!(strata, (0..n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn samples_are_in_unit_interval() {
+        // Past the dimensions generated by the Halton sequence and Sobol's first padding group
+        for kind in KINDS {
+            for sample in samples(kind, 64, 300) {
+                assert!(
+                    sample.iter().all(|&u| (0.0..1.0).contains(&u)),
+                    "{:?}",
+                    kind
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn samples_only_depend_on_pixel_and_index() {
+        for kind in KINDS {
+            let mut sampler = kind.create(7, 16);
+            sampler.start_sample(3, 5, 9);
+            let first: Vec<f32> = (0..10).map(|_| sampler.get_1d()).collect();
+            sampler.start_sample(4, 5, 9);
+            let other_pixel: Vec<f32> = (0..10).map(|_| sampler.get_1d()).collect();
+            sampler.start_sample(3, 5, 9);
+            let again: Vec<f32> = (0..10).map(|_| sampler.get_1d()).collect();
+            assert_eq!(first, again, "{:?}", kind);
+            assert_ne!(first, other_pixel, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn stratified_samples_cover_every_stratum() {
+        let spp = 16;
+        let samples = samples(SamplerKind::Stratified, spp, 4);
+        for dim in 0..4 {
+            assert_stratified(samples.iter().map(|sample| sample[dim]), spp);
+        }
+
+        let mut sampler = SamplerKind::Stratified.create(7, spp);
+        let cells = (0..spp).map(|index| {
+            sampler.start_sample(3, 5, index);
+            let (u, v) = sampler.get_2d();
+            (stratum(u, 4) * 4 + stratum(v, 4)) as f32 / spp as f32
+        });
+        assert_stratified(cells, spp);
+    }
+
+    #[test]
+    fn sobol_samples_are_stratified() {
+        let spp = 16;
+        let samples = samples(SamplerKind::Sobol, spp, 8);
+        for dim in 0..8 {
+            assert_stratified(samples.iter().map(|sample| sample[dim]), spp);
+        }
+
+        // The first two dimensions also form a (0, 4, 2)-net
+        let mut sampler = SamplerKind::Sobol.create(7, spp);
+        let cells = (0..spp).map(|index| {
+            sampler.start_sample(3, 5, index);
+            let (u, v) = sampler.get_2d();
+            (stratum(u, 4) * 4 + stratum(v, 4)) as f32 / spp as f32
+        });
+        assert_stratified(cells, spp);
+    }
+
+    #[test]
+    fn halton_samples_are_stratified() {
+        // Dimension d stratifies in powers of the d-th prime
+        let samples = samples(SamplerKind::Halton, 27, 2);
+        assert_stratified(samples[..16].iter().map(|sample| sample[0]), 16);
+        assert_stratified(samples.iter().map(|sample| sample[1]), 27);
+    }
+}
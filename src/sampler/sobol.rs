@@ -0,0 +1,126 @@
+use lazy_static::lazy_static;
+
+use crate::random::mix_bits;
+use crate::sampler::{pixel_hash, Sampler, ONE_MINUS_EPSILON};
+
+/// Number of Sobol dimensions generated from a single (shuffled) sample index. Higher dimensions
+/// are obtained by "padding": each group of `SOBOL_DIMENSIONS` dimensions uses its own shuffling
+/// and scrambling seeds.
+const SOBOL_DIMENSIONS: usize = 4;
+
+/// Parameters of the primitive polynomials for the first Sobol dimensions (after the first one,
+/// which is the van der Corput sequence), from Joe & Kuo: (degree, coefficients, initial
+/// direction numbers).
+const SOBOL_POLYNOMIALS: [(u32, u32, [u32; 3]); SOBOL_DIMENSIONS - 1] =
+    [(1, 0, [1, 0, 0]), (2, 1, [1, 3, 0]), (3, 1, [1, 3, 1])];
+
+lazy_static! {
+    static ref DIRECTIONS: [[u32; 32]; SOBOL_DIMENSIONS] = generate_directions();
+}
+
+/// Sampler based on the Sobol sequence, with hash-based Owen scrambling and index shuffling
+/// (Burley, "Practical Hash-based Owen Scrambling"). Each pixel uses its own scrambling seed.
+pub struct SobolSampler {
+    seed: u64,
+    pixel_hash: u64,
+    index: u32,
+    dim: usize,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler {
+            seed,
+            pixel_hash: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+
+    fn sample(&self, dim: usize) -> f32 {
+        let group_seed = mix_bits(self.pixel_hash ^ mix_bits((dim / SOBOL_DIMENSIONS) as u64));
+        let index = nested_uniform_scramble(self.index, group_seed as u32);
+        let component = dim % SOBOL_DIMENSIONS;
+        let value = nested_uniform_scramble(
+            sobol(index, component),
+            mix_bits(group_seed ^ component as u64) as u32,
+        );
+
+        f32::min(value as f32 * (1.0 / 4_294_967_296.0), ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel_hash = pixel_hash(self.seed, x, y);
+        self.index = index as u32;
+        self.dim = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let value = self.sample(self.dim);
+        self.dim += 1;
+
+        value
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // Both dimensions need to come from the same group to be well distributed in 2D.
+        if self.dim % SOBOL_DIMENSIONS == SOBOL_DIMENSIONS - 1 {
+            self.dim += 1;
+        }
+        let value = (self.sample(self.dim), self.sample(self.dim + 1));
+        self.dim += 2;
+
+        value
+    }
+}
+
+fn sobol(mut index: u32, dim: usize) -> u32 {
+    let mut x = 0;
+    let mut bit = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= DIRECTIONS[dim][bit];
+        }
+        index >>= 1;
+        bit += 1;
+    }
+    x
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn generate_directions() -> [[u32; 32]; SOBOL_DIMENSIONS] {
+    let mut directions = [[0; 32]; SOBOL_DIMENSIONS];
+    for (k, v) in directions[0].iter_mut().enumerate() {
+        *v = 1 << (31 - k);
+    }
+    for (dim, &(s, a, m)) in SOBOL_POLYNOMIALS.iter().enumerate() {
+        let s = s as usize;
+        let v = &mut directions[dim + 1];
+        for k in 0..s {
+            v[k] = m[k] << (31 - k);
+        }
+        for k in s..32 {
+            v[k] = v[k - s] ^ (v[k - s] >> s);
+            for j in 1..s {
+                if (a >> (s - 1 - j)) & 1 != 0 {
+                    v[k] ^= v[k - j];
+                }
+            }
+        }
+    }
+    directions
+}
@@ -0,0 +1,78 @@
+use rand::Rng;
+
+use crate::random::{mix_bits, sample_rng, seeded_rng, RenderRng};
+use crate::sampler::{permutation_element, pixel_hash, Sampler, ONE_MINUS_EPSILON};
+
+/// Sampler splitting every dimension into as many strata as there are samples per pixel, and
+/// placing one jittered sample in each stratum. The strata are visited in a different random
+/// order for each dimension so that dimensions aren't correlated.
+pub struct StratifiedSampler {
+    seed: u64,
+    spp: u32,
+    /// Number of strata along each axis for 2D samples
+    nx: u32,
+    ny: u32,
+    pixel_hash: u64,
+    index: u32,
+    dim: u64,
+    rng: RenderRng,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, spp: usize) -> StratifiedSampler {
+        let spp = spp.max(1) as u32;
+        let nx = f32::ceil(f32::sqrt(spp as f32)) as u32;
+        let ny = spp.div_ceil(nx);
+        StratifiedSampler {
+            seed,
+            spp,
+            nx,
+            ny,
+            pixel_hash: 0,
+            index: 0,
+            dim: 0,
+            rng: seeded_rng(seed),
+        }
+    }
+
+    fn next_hash(&mut self) -> u32 {
+        let hash = mix_bits(self.pixel_hash ^ mix_bits(self.dim)) as u32;
+        self.dim += 1;
+        hash
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel_hash = pixel_hash(self.seed, x, y);
+        self.index = index as u32;
+        self.dim = 0;
+        self.rng = sample_rng(self.seed, x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let hash = self.next_hash();
+        let stratum = permutation_element(self.index % self.spp, self.spp, hash);
+        let delta = self.rng.gen::<f32>();
+
+        f32::min(
+            (stratum as f32 + delta) / self.spp as f32,
+            ONE_MINUS_EPSILON,
+        )
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // When spp isn't a multiple of nx, some of the cells of the grid don't get a sample. They
+        // are still part of the permutation so that every cell is equally likely to be chosen.
+        let hash = self.next_hash();
+        let cells = self.nx * self.ny;
+        let stratum = permutation_element(self.index % cells, cells, hash);
+        let (sx, sy) = (stratum % self.nx, stratum / self.nx);
+        let (dx, dy) = (self.rng.gen::<f32>(), self.rng.gen::<f32>());
+
+        (
+            f32::min((sx as f32 + dx) / self.nx as f32, ONE_MINUS_EPSILON),
+            f32::min((sy as f32 + dy) / self.ny as f32, ONE_MINUS_EPSILON),
+        )
+    }
+}
@@ -0,0 +1,33 @@
+use rand::Rng;
+
+use crate::random::{sample_rng, seeded_rng, RenderRng};
+use crate::sampler::Sampler;
+
+/// Sampler returning independent uniform random numbers for every dimension.
+pub struct UniformSampler {
+    seed: u64,
+    rng: RenderRng,
+}
+
+impl UniformSampler {
+    pub fn new(seed: u64) -> UniformSampler {
+        UniformSampler {
+            seed,
+            rng: seeded_rng(seed),
+        }
+    }
+}
+
+impl Sampler for UniformSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.rng = sample_rng(self.seed, x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
@@ -0,0 +1,113 @@
+use std::cell::Cell;
+use std::sync::Arc;
+
+use log::info;
+
+use crate::bvh::BvhNode;
+use crate::environment::Environment;
+use crate::hitable::{HitRecord, Hitable, Tagged};
+use crate::random::RenderRng;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec::Vec3;
+
+thread_local! {
+    /// Number of rays traced by this thread since `take_ray_count` was last called
+    static RAYS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Return the number of rays traced by the current thread since the last call.
+pub fn take_ray_count() -> u64 {
+    RAYS.with(|rays| rays.replace(0))
+}
+
+/// Everything the integrators need to know about the scene being rendered.
+pub struct Scene {
+    /// Acceleration structure containing all the objects of the scene
+    pub world: BvhNode,
+    /// The objects of the scene which emit light and can be sampled directly
+    pub lights: Vec<Arc<dyn Hitable>>,
+    /// Light arriving from the directions in which rays escape the scene
+    pub environment: Arc<dyn Environment>,
+}
+
+impl Scene {
+    pub fn new(
+        objects: Vec<Arc<dyn Hitable>>,
+        environment: Arc<dyn Environment>,
+        time0: f32,
+        time1: f32,
+        rng: &mut RenderRng,
+    ) -> Scene {
+        let lights: Vec<Arc<dyn Hitable>> = objects
+            .iter()
+            .filter(|object| object.is_emissive())
+            .cloned()
+            .collect();
+        info!(
+            "Scene contains {} objects, {} of which are lights",
+            objects.len(),
+            lights.len()
+        );
+        // Number the objects from 1, so that 0 means that nothing was hit
+        let mut objects: Vec<Arc<dyn Hitable>> = objects
+            .into_iter()
+            .enumerate()
+            .map(|(i, object)| Arc::new(Tagged::new(object, i as u32 + 1)) as Arc<dyn Hitable>)
+            .collect();
+        let world = BvhNode::new(&mut objects[..], time0, time1, rng);
+
+        Scene {
+            world,
+            lights,
+            environment,
+        }
+    }
+
+    /// Find the closest intersection of `ray` with the objects of the scene, like `Hitable::hit`,
+    /// counting the ray.
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        RAYS.with(|rays| rays.set(rays.get() + 1));
+        self.world.hit(ray, t_min, t_max, rec)
+    }
+
+    /// Number of lights which can be sampled directly, counting the environment as one.
+    fn light_count(&self) -> usize {
+        self.lights.len() + usize::from(self.environment.is_sampleable())
+    }
+
+    /// Whether there is any light to sample directly.
+    pub fn has_lights(&self) -> bool {
+        self.light_count() > 0
+    }
+
+    /// Return the density with which `random_light_direction` generates `direction` from `o` at
+    /// time `time`.
+    pub fn light_pdf_value(&self, o: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let count = self.light_count();
+        if count == 0 {
+            return 0.0;
+        }
+        let mut sum: f32 = self
+            .lights
+            .iter()
+            .map(|light| light.pdf_value(o, direction, time))
+            .sum();
+        if self.environment.is_sampleable() {
+            sum += self.environment.pdf_value(direction);
+        }
+
+        sum / count as f32
+    }
+
+    /// Pick one of the lights uniformly, the environment included, and generate a direction from
+    /// `o` towards it at time `time`.
+    pub fn random_light_direction(&self, o: &Vec3, time: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let count = self.light_count();
+        let index = ((sampler.get_1d() * count as f32) as usize).min(count - 1);
+        match self.lights.get(index) {
+            Some(light) => light.random(o, time, sampler),
+            None => self.environment.random(sampler),
+        }
+    }
+}
@@ -0,0 +1,938 @@
+//! Scene files: TOML descriptions of the camera, textures, materials and objects of a scene, and
+//! of the render settings it needs, so that scenes can be changed without recompiling. Textures
+//! and materials are declared by name, and objects refer to them:
+//!
+//! ```toml
+//! [camera]
+//! lookfrom = [13, 2, 3]
+//! lookat = [0, 0, 0]
+//! fov = 20
+//!
+//! [settings]
+//! width = 400
+//! height = 200
+//!
+//! [environment]
+//! type = "sky"
+//!
+//! [textures.marble]
+//! type = "noise"
+//! scale = 4
+//!
+//! [materials.ground]
+//! type = "lambertian"
+//! albedo = "marble"
+//!
+//! [materials.light]
+//! type = "diffuse_light"
+//! emit = [4, 4, 4]
+//!
+//! [[objects]]
+//! type = "sphere"
+//! center = [0, -1000, 0]
+//! radius = 1000
+//! material = "ground"
+//! ```
+//!
+//! Wherever a texture is expected, a colour `[r, g, b]` can be given instead of a name. Camera
+//! parameters can be keyframed with a list of `[time, value]` pairs instead of a single value,
+//! and `animated` objects move by a keyframed offset. Relative paths of image and grid files are
+//! relative to the scene file, and files which can't be loaded are errors at their path.
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::animation::{Animated, CameraRig, Keyframes, Lerp};
+use crate::aov::Aov;
+use crate::density::{Density, GridDensity, NoiseDensity};
+use crate::environment::{ConstantEnvironment, Environment, GradientEnvironment, MapEnvironment};
+use crate::filter::FilterKind;
+use crate::hitable::*;
+use crate::integrator::IntegratorKind;
+use crate::material::{
+    Dielectric, DiffuseLight, HenyeyGreenstein, Isotropic, Lambertian, Material, Metal,
+};
+use crate::render::{AdaptiveSampling, CropWindow, RenderSettings};
+use crate::sampler::SamplerKind;
+use crate::texture::*;
+use crate::tonemap::{OutputTransform, ToneMapper};
+use crate::vec::Vec3;
+
+/// A scene loaded from a file.
+pub struct SceneFile {
+    pub objects: Vec<Arc<dyn Hitable>>,
+    pub environment: Arc<dyn Environment>,
+    pub rig: CameraRig,
+    pub settings: SceneSettings,
+    /// Hash of the text of the file and of the files it refers to, which identifies the scene
+    pub hash: u64,
+}
+
+/// Why a scene file couldn't be loaded, and where in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SceneError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Render settings given by a scene file. Those which aren't given keep their defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneSettings {
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub samples: Option<usize>,
+    pub seed: Option<u64>,
+    pub sampler: Option<SamplerKind>,
+    pub integrator: Option<IntegratorKind>,
+    /// Reconstruction filter, e.g. `"tent"` or `{ mitchell = { b = 0.3, c = 0.35 } }`, given the
+    /// usual radius for its kind unless `filter_radius` is given
+    pub filter: Option<FilterKind>,
+    pub filter_radius: Option<f32>,
+    /// Number of bounces after which paths may be terminated by Russian roulette
+    pub min_depth: Option<u32>,
+    /// Number of bounces after which paths are always terminated
+    pub max_depth: Option<u32>,
+    pub pass_samples: Option<usize>,
+    /// Time after which the render stops, in seconds
+    pub time_limit: Option<f32>,
+    /// Mean relative error of the pixels below which the render stops
+    pub noise_target: Option<f32>,
+    /// Whether to sample noisy pixels more, with the default parameters
+    pub adaptive: Option<bool>,
+    pub denoise: Option<bool>,
+    /// How aggressively the denoiser smooths noise out, 1 by default
+    pub denoise_strength: Option<f32>,
+    /// Part of the image to render, `[x0, y0, x1, y1]` in pixels, or in fractions of the image
+    /// if given as decimals
+    pub crop: Option<CropWindow>,
+    /// Whether a cropped render gives an image of the full size, black outside the window
+    pub crop_full_frame: Option<bool>,
+    /// Names of the AOVs to write next to the image, e.g. `["normal", "albedo"]`
+    pub aovs: Option<Vec<Aov>>,
+    /// Exposure compensation of the image, in stops
+    pub exposure: Option<f32>,
+    pub tone_mapper: Option<ToneMapper>,
+}
+
+impl SceneSettings {
+    /// Return the settings of the render, starting from `defaults`.
+    pub fn apply(&self, defaults: &RenderSettings) -> RenderSettings {
+        let mut settings = defaults.clone();
+        settings.width = self.width.unwrap_or(settings.width);
+        settings.height = self.height.unwrap_or(settings.height);
+        settings.samples = self.samples.unwrap_or(settings.samples);
+        settings.seed = self.seed.unwrap_or(settings.seed);
+        settings.sampler = self.sampler.unwrap_or(settings.sampler);
+        settings.integrator = self.integrator.unwrap_or(settings.integrator);
+        if let Some(filter) = self.filter {
+            settings.filter = filter;
+            settings.filter_radius = filter.default_radius();
+        }
+        settings.filter_radius = self.filter_radius.unwrap_or(settings.filter_radius);
+        settings.depth.min_depth = self.min_depth.unwrap_or(settings.depth.min_depth);
+        settings.depth.max_depth = self.max_depth.unwrap_or(settings.depth.max_depth);
+        settings.pass_samples = self.pass_samples.unwrap_or(settings.pass_samples);
+        if let Some(seconds) = self.time_limit {
+            settings.time_limit = Some(Duration::from_secs_f32(seconds));
+        }
+        if let Some(target) = self.noise_target {
+            settings.noise_target = Some(target);
+        }
+        if let Some(adaptive) = self.adaptive {
+            settings.adaptive = adaptive.then(AdaptiveSampling::default);
+        }
+        settings.denoise = self.denoise.unwrap_or(settings.denoise);
+        settings.denoise_strength = self.denoise_strength.unwrap_or(settings.denoise_strength);
+        if let Some(crop) = self.crop {
+            settings.crop = Some(crop);
+        }
+        settings.crop_full_frame = self.crop_full_frame.unwrap_or(settings.crop_full_frame);
+        if let Some(aovs) = &self.aovs {
+            settings.aovs = aovs.clone();
+        }
+        settings
+    }
+
+    pub fn transform(&self, defaults: &OutputTransform) -> OutputTransform {
+        OutputTransform {
+            exposure: self.exposure.unwrap_or(defaults.exposure),
+            tone_mapper: self.tone_mapper.unwrap_or(defaults.tone_mapper),
+        }
+    }
+
+    /// Check that the settings make sense, and return why if they don't.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("the image size must be positive".to_string());
+        }
+        if self.samples == Some(0) || self.pass_samples == Some(0) {
+            return Err("the number of samples must be positive".to_string());
+        }
+        if self
+            .filter_radius
+            .is_some_and(|r| !(r > 0.0 && r.is_finite()))
+        {
+            return Err("the filter radius must be positive".to_string());
+        }
+        if self
+            .denoise_strength
+            .is_some_and(|s| !(s >= 0.0 && s.is_finite()))
+        {
+            return Err("the denoising strength must not be negative".to_string());
+        }
+        if self.time_limit.is_some_and(|t| !(t > 0.0 && t < 1e9)) {
+            return Err("the time limit must be a positive number of seconds".to_string());
+        }
+        if self
+            .noise_target
+            .is_some_and(|e| !(e > 0.0 && e.is_finite()))
+        {
+            return Err("the noise target must be positive".to_string());
+        }
+        if let Some(crop) = &self.crop {
+            crop.validate()?;
+        }
+        if self.max_depth == Some(0) {
+            return Err("the maximum depth must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl SceneFile {
+    /// Load the scene file at `path`. Errors are given as `path:line:column: message`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SceneFile, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        SceneFile::parse(&text, directory)
+            .map_err(|e| format!("{}:{}:{}: {}", path.display(), e.line, e.column, e.message))
+    }
+
+    /// Load a scene from the text of a scene file, whose relative paths are relative to
+    /// `directory`.
+    pub fn parse(text: &str, directory: &Path) -> Result<SceneFile, SceneError> {
+        SceneFile::load(text, directory, false)
+    }
+
+    /// Load a scene like `parse`, from text which isn't trusted: its paths must be relative, and
+    /// can't leave `directory`.
+    pub fn parse_confined(text: &str, directory: &Path) -> Result<SceneFile, SceneError> {
+        SceneFile::load(text, directory, true)
+    }
+
+    fn load(text: &str, directory: &Path, confined: bool) -> Result<SceneFile, SceneError> {
+        let spec: SceneSpec = toml::from_str(text).map_err(|e| {
+            // Syntax errors are explained over several lines
+            let message = e.message().trim().replace('\n', ", ");
+            error_at(text, e.span().unwrap_or(0..0), message)
+        })?;
+        let at = |span: Range<usize>| move |message: String| error_at(text, span, message);
+
+        let settings = match spec.settings {
+            Some(settings) => {
+                settings.get_ref().validate().map_err(at(settings.span()))?;
+                settings.into_inner()
+            }
+            None => SceneSettings::default(),
+        };
+        let rig = spec
+            .camera
+            .get_ref()
+            .rig()
+            .map_err(at(spec.camera.span()))?;
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let mut loader = Loader {
+            text,
+            directory,
+            confined,
+            hasher: RefCell::new(hasher),
+            texture_specs: &spec.textures,
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+        };
+        let environment = match &spec.environment {
+            Some(environment) => loader.environment(environment.get_ref(), &environment.span())?,
+            None => Arc::new(ConstantEnvironment::default()),
+        };
+        // Every declaration is checked, even if nothing refers to it
+        for (name, texture) in &spec.textures {
+            loader.texture(name, &texture.span(), &mut Vec::new())?;
+        }
+        for (name, material) in &spec.materials {
+            let built = loader.material(material.get_ref(), &material.span())?;
+            loader.materials.insert(name.clone(), built);
+        }
+        if spec.objects.is_empty() {
+            return Err(error_at(text, 0..0, "the scene has no objects".to_string()));
+        }
+        let objects = spec
+            .objects
+            .iter()
+            .map(|object| loader.object(object.get_ref(), &object.span()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(SceneFile {
+            objects,
+            environment,
+            rig,
+            settings,
+            hash: loader.hasher.into_inner().finish(),
+        })
+    }
+}
+
+/// Add the contents of the file at `path` to `hasher`.
+fn hash_file(path: &Path, hasher: &mut DefaultHasher) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(()),
+            n => hasher.write(&buffer[..n]),
+        }
+    }
+}
+
+/// Return an error at the start of `span` in `text`.
+fn error_at(text: &str, span: Range<usize>, message: String) -> SceneError {
+    let before = &text[..span.start.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    SceneError {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+        message,
+    }
+}
+
+/// The contents of a scene file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneSpec {
+    camera: Spanned<CameraSpec>,
+    settings: Option<Spanned<SceneSettings>>,
+    /// Light coming from infinitely far away, black if not given
+    environment: Option<Spanned<EnvironmentSpec>>,
+    #[serde(default)]
+    textures: BTreeMap<String, Spanned<TextureSpec>>,
+    #[serde(default)]
+    materials: BTreeMap<String, Spanned<MaterialSpec>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectSpec>>,
+}
+
+/// A value which is either constant, or keyframed with `[time, value]` pairs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a value, or a list of [time, value] keyframes"
+)]
+enum Animatable<T> {
+    Constant(T),
+    Keyframed(Vec<(f32, T)>),
+}
+
+impl<T: Lerp> Animatable<T> {
+    fn keyframes(&self) -> Result<Keyframes<T>, String> {
+        match self {
+            Animatable::Constant(value) => Ok(Keyframes::constant(*value)),
+            Animatable::Keyframed(keys) if keys.is_empty() => Err("no keyframes".to_string()),
+            Animatable::Keyframed(keys) => Ok(Keyframes::new(keys.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraSpec {
+    lookfrom: Animatable<Vec3>,
+    lookat: Animatable<Vec3>,
+    #[serde(default = "default_vup")]
+    vup: Vec3,
+    /// Vertical field of view, in degrees
+    fov: Animatable<f32>,
+    #[serde(default)]
+    aperture: f32,
+    #[serde(default = "default_focus_distance")]
+    focus_distance: Animatable<f32>,
+}
+
+fn default_vup() -> Vec3 {
+    Vec3::new(0.0, 1.0, 0.0)
+}
+
+fn default_focus_distance() -> Animatable<f32> {
+    Animatable::Constant(10.0)
+}
+
+impl CameraSpec {
+    fn rig(&self) -> Result<CameraRig, String> {
+        Ok(CameraRig {
+            lookfrom: self.lookfrom.keyframes()?,
+            lookat: self.lookat.keyframes()?,
+            vup: self.vup,
+            fov: self.fov.keyframes()?,
+            aperture: self.aperture,
+            focus_distance: self.focus_distance.keyframes()?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum EnvironmentSpec {
+    Constant {
+        color: Vec3,
+    },
+    Gradient {
+        bottom: Vec3,
+        top: Vec3,
+    },
+    /// The white to light blue sky of "Ray Tracing In One Weekend"
+    Sky,
+    Map {
+        path: PathBuf,
+        /// Rotation around the vertical axis, in radians
+        #[serde(default)]
+        rotation: f32,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+/// A texture given by name, or a constant colour.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a colour [r, g, b] or the name of a texture"
+)]
+enum TextureRef {
+    Color(Vec3),
+    Name(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureSpec {
+    Constant { color: Vec3 },
+    Checker { odd: TextureRef, even: TextureRef },
+    Noise { scale: f32 },
+    Image { path: PathBuf },
+    HdrImage { path: PathBuf },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialSpec {
+    Lambertian {
+        albedo: TextureRef,
+    },
+    Metal {
+        albedo: Vec3,
+        #[serde(default)]
+        fuzz: f32,
+    },
+    Dielectric {
+        ref_idx: f32,
+    },
+    DiffuseLight {
+        emit: TextureRef,
+    },
+    Isotropic {
+        albedo: TextureRef,
+    },
+    HenyeyGreenstein {
+        albedo: TextureRef,
+        g: f32,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum DensitySpec {
+    Noise { scale: f32, density: f32 },
+    Grid { path: PathBuf, min: Vec3, max: Vec3 },
+}
+
+/// An object. Rectangles span the two ranges of coordinates in their name, at the given value of
+/// the third coordinate, and face towards it increasing unless flipped.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectSpec {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        material: String,
+    },
+    MovingSphere {
+        center0: Vec3,
+        center1: Vec3,
+        #[serde(default)]
+        time0: f32,
+        #[serde(default = "default_time1")]
+        time1: f32,
+        radius: f32,
+        material: String,
+    },
+    XyRect {
+        x: [f32; 2],
+        y: [f32; 2],
+        z: f32,
+        material: String,
+        #[serde(default)]
+        flip: bool,
+    },
+    XzRect {
+        x: [f32; 2],
+        z: [f32; 2],
+        y: f32,
+        material: String,
+        #[serde(default)]
+        flip: bool,
+    },
+    YzRect {
+        y: [f32; 2],
+        z: [f32; 2],
+        x: f32,
+        material: String,
+        #[serde(default)]
+        flip: bool,
+    },
+    #[serde(rename = "box")]
+    Boxx {
+        min: Vec3,
+        max: Vec3,
+        material: String,
+    },
+    ConstantMedium {
+        boundary: Box<ObjectSpec>,
+        density: f32,
+        albedo: TextureRef,
+    },
+    HeterogeneousMedium {
+        boundary: Box<ObjectSpec>,
+        density: DensitySpec,
+        /// Name of the material giving the phase function
+        phase: String,
+    },
+    /// An object moved by a keyframed offset
+    Animated {
+        object: Box<ObjectSpec>,
+        offset: Animatable<Vec3>,
+    },
+}
+
+fn default_time1() -> f32 {
+    1.0
+}
+
+/// Builds the parts of a scene from their specs, sharing those declared by name.
+struct Loader<'a> {
+    /// Text of the scene file, to locate errors
+    text: &'a str,
+    directory: &'a Path,
+    /// Whether paths must stay within `directory`
+    confined: bool,
+    /// Hash of the text and of the files loaded so far
+    hasher: RefCell<DefaultHasher>,
+    texture_specs: &'a BTreeMap<String, Spanned<TextureSpec>>,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+}
+
+impl Loader<'_> {
+    fn error(&self, span: &Range<usize>, message: String) -> SceneError {
+        error_at(self.text, span.clone(), message)
+    }
+
+    /// Load the file at `path`, which the declaration at `span` refers to, with `open`. Errors
+    /// are located at the path.
+    fn load<T, E, F>(&self, path: &Path, span: &Range<usize>, open: F) -> Result<T, SceneError>
+    where
+        E: Display,
+        F: FnOnce(&Path) -> Result<T, E>,
+    {
+        let span = self.path_span(path, span);
+        let within = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if self.confined && !within {
+            let message = format!(
+                "path \"{}\" is outside the scenes directory",
+                path.display()
+            );
+            return Err(self.error(&span, message));
+        }
+
+        let full_path = self.directory.join(path);
+        let fail = |e: &dyn Display| {
+            let message = format!("failed to load \"{}\": {}", path.display(), e);
+            self.error(&span, message)
+        };
+        let loaded = open(&full_path).map_err(|e| fail(&e))?;
+        hash_file(&full_path, &mut self.hasher.borrow_mut()).map_err(|e| fail(&e))?;
+        Ok(loaded)
+    }
+
+    /// Return the span of the string giving `path` in the declaration at `span`, or `span` if
+    /// it can't be found, e.g. because it has escapes.
+    fn path_span(&self, path: &Path, span: &Range<usize>) -> Range<usize> {
+        let declaration = &self.text[span.clone()];
+        let quoted = path.to_str().and_then(|path| {
+            ['"', '\''].into_iter().find_map(|quote| {
+                let quoted = format!("{}{}{}", quote, path, quote);
+                let start = declaration.find(&quoted)?;
+                Some(span.start + start..span.start + start + quoted.len())
+            })
+        });
+        quoted.unwrap_or(span.clone())
+    }
+
+    fn environment(
+        &self,
+        spec: &EnvironmentSpec,
+        span: &Range<usize>,
+    ) -> Result<Arc<dyn Environment>, SceneError> {
+        Ok(match spec {
+            EnvironmentSpec::Constant { color } => Arc::new(ConstantEnvironment::new(*color)),
+            EnvironmentSpec::Gradient { bottom, top } => {
+                Arc::new(GradientEnvironment::new(*bottom, *top))
+            }
+            EnvironmentSpec::Sky => Arc::new(GradientEnvironment::sky()),
+            EnvironmentSpec::Map {
+                path,
+                rotation,
+                intensity,
+            } => Arc::new(self.load(path, span, |path| {
+                MapEnvironment::open(path, *rotation, *intensity)
+            })?),
+        })
+    }
+
+    /// Return the texture declared as `name`, which is referred to at `span`, building it and
+    /// the textures it refers to if they aren't built yet. `pending` holds the textures being
+    /// built, which refer to this one.
+    fn texture(
+        &mut self,
+        name: &str,
+        span: &Range<usize>,
+        pending: &mut Vec<String>,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        if let Some(texture) = self.textures.get(name) {
+            return Ok(texture.clone());
+        }
+        let spec = match self.texture_specs.get(name) {
+            Some(spec) => spec,
+            None => return Err(self.error(span, format!("unknown texture \"{}\"", name))),
+        };
+        if pending.iter().any(|pending| pending == name) {
+            let message = format!("texture \"{}\" refers to itself", name);
+            return Err(self.error(&spec.span(), message));
+        }
+
+        pending.push(name.to_string());
+        let span = spec.span();
+        let texture: Arc<dyn Texture> = match spec.get_ref() {
+            TextureSpec::Constant { color } => Arc::new(ConstantTexture::new(*color)),
+            TextureSpec::Checker { odd, even } => Arc::new(CheckerTexture::new(
+                self.texture_ref(odd, &span, pending)?,
+                self.texture_ref(even, &span, pending)?,
+            )),
+            TextureSpec::Noise { scale } => Arc::new(NoiseTexture::new(*scale)),
+            TextureSpec::Image { path } => {
+                Arc::new(self.load(path, &span, |path| ImageTexture::open(path))?)
+            }
+            TextureSpec::HdrImage { path } => {
+                Arc::new(self.load(path, &span, |path| HdrImageTexture::open(path))?)
+            }
+        };
+        pending.pop();
+
+        self.textures.insert(name.to_string(), texture.clone());
+        Ok(texture)
+    }
+
+    fn texture_ref(
+        &mut self,
+        texture: &TextureRef,
+        span: &Range<usize>,
+        pending: &mut Vec<String>,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        match texture {
+            TextureRef::Color(color) => Ok(Arc::new(ConstantTexture::new(*color))),
+            TextureRef::Name(name) => self.texture(name, span, pending),
+        }
+    }
+
+    /// Build the material declared at `span`.
+    fn material(
+        &mut self,
+        spec: &MaterialSpec,
+        span: &Range<usize>,
+    ) -> Result<Arc<dyn Material>, SceneError> {
+        let mut texture = |texture| self.texture_ref(texture, span, &mut Vec::new());
+        Ok(match spec {
+            MaterialSpec::Lambertian { albedo } => Arc::new(Lambertian::new(texture(albedo)?)),
+            MaterialSpec::Metal { albedo, fuzz } => Arc::new(Metal::new(*albedo, *fuzz)),
+            MaterialSpec::Dielectric { ref_idx } => Arc::new(Dielectric::new(*ref_idx)),
+            MaterialSpec::DiffuseLight { emit } => Arc::new(DiffuseLight::new(texture(emit)?)),
+            MaterialSpec::Isotropic { albedo } => Arc::new(Isotropic::new(texture(albedo)?)),
+            MaterialSpec::HenyeyGreenstein { albedo, g } => {
+                Arc::new(HenyeyGreenstein::new(texture(albedo)?, *g))
+            }
+        })
+    }
+
+    /// Return the material declared as `name`, which is referred to at `span`.
+    fn material_ref(
+        &self,
+        name: &str,
+        span: &Range<usize>,
+    ) -> Result<Arc<dyn Material>, SceneError> {
+        self.materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| self.error(span, format!("unknown material \"{}\"", name)))
+    }
+
+    /// Build the object declared at `span`.
+    fn object(
+        &mut self,
+        spec: &ObjectSpec,
+        span: &Range<usize>,
+    ) -> Result<Arc<dyn Hitable>, SceneError> {
+        let material = |name: &String| self.material_ref(name, span);
+        let flipped = |rect: Arc<dyn Hitable>, flip: bool| -> Arc<dyn Hitable> {
+            if flip {
+                Arc::new(FlipNormals::new(rect))
+            } else {
+                rect
+            }
+        };
+        Ok(match spec {
+            ObjectSpec::Sphere {
+                center,
+                radius,
+                material: name,
+            } => Arc::new(Sphere::new(*center, *radius, material(name)?)),
+            ObjectSpec::MovingSphere {
+                center0,
+                center1,
+                time0,
+                time1,
+                radius,
+                material: name,
+            } => Arc::new(MovingSphere::new(
+                *center0,
+                *center1,
+                *time0,
+                *time1,
+                *radius,
+                material(name)?,
+            )),
+            ObjectSpec::XyRect {
+                x,
+                y,
+                z,
+                material: name,
+                flip,
+            } => flipped(
+                Arc::new(XYRect::new(x[0], x[1], y[0], y[1], *z, material(name)?)),
+                *flip,
+            ),
+            ObjectSpec::XzRect {
+                x,
+                z,
+                y,
+                material: name,
+                flip,
+            } => flipped(
+                Arc::new(XZRect::new(x[0], x[1], z[0], z[1], *y, material(name)?)),
+                *flip,
+            ),
+            ObjectSpec::YzRect {
+                y,
+                z,
+                x,
+                material: name,
+                flip,
+            } => flipped(
+                Arc::new(YZRect::new(y[0], y[1], z[0], z[1], *x, material(name)?)),
+                *flip,
+            ),
+            ObjectSpec::Boxx {
+                min,
+                max,
+                material: name,
+            } => Arc::new(Boxx::new(*min, *max, material(name)?)),
+            ObjectSpec::ConstantMedium {
+                boundary,
+                density,
+                albedo,
+            } => {
+                let boundary = self.object(boundary, span)?;
+                let albedo = self.texture_ref(albedo, span, &mut Vec::new())?;
+                Arc::new(ConstantMedium::new(boundary, *density, albedo))
+            }
+            ObjectSpec::HeterogeneousMedium {
+                boundary,
+                density,
+                phase,
+            } => {
+                let phase = material(phase)?;
+                let density: Arc<dyn Density> = match density {
+                    DensitySpec::Noise { scale, density } => {
+                        Arc::new(NoiseDensity::new(*scale, *density))
+                    }
+                    DensitySpec::Grid { path, min, max } => {
+                        Arc::new(self.load(path, span, |path| GridDensity::open(path, *min, *max))?)
+                    }
+                };
+                Arc::new(HeterogeneousMedium::new(
+                    self.object(boundary, span)?,
+                    density,
+                    phase,
+                ))
+            }
+            ObjectSpec::Animated { object, offset } => {
+                let offset = offset.keyframes().map_err(|e| self.error(span, e))?;
+                Arc::new(Animated::new(self.object(object, span)?, offset))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"
+[camera]
+lookfrom = [0, 0, 5]
+lookat = [0, 0, 0]
+fov = 40
+
+[materials.white]
+type = "lambertian"
+albedo = [0.7, 0.7, 0.7]
+
+[[objects]]
+type = "sphere"
+center = [0, 0, 0]
+radius = 1
+material = "white"
+"#;
+
+    fn parse_error(text: &str) -> SceneError {
+        match SceneFile::parse(text, Path::new("")) {
+            Ok(_) => panic!("the scene should be rejected"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parses_valid_scene() {
+        let file = SceneFile::parse(SCENE, Path::new("")).unwrap();
+        assert_eq!(file.objects.len(), 1);
+    }
+
+    #[test]
+    fn locates_syntax_error() {
+        let e = parse_error(&SCENE.replace("[0.7, 0.7, 0.7]", "[0.7, 0.7 0.7]"));
+        assert_eq!((e.line, e.column), (9, 20));
+        assert_eq!(e.message, "invalid array, expected `]`");
+    }
+
+    #[test]
+    fn locates_value_of_wrong_type() {
+        // Errors within an object are located at the start of the object
+        let e = parse_error(&SCENE.replace("radius = 1", "radius = \"big\""));
+        assert_eq!((e.line, e.column), (11, 1));
+        assert_eq!(e.message, "invalid type: string \"big\", expected f32");
+    }
+
+    #[test]
+    fn locates_unknown_material() {
+        let e = parse_error(&SCENE.replace("material = \"white\"", "material = \"black\""));
+        assert_eq!((e.line, e.column), (11, 1));
+        assert_eq!(e.message, "unknown material \"black\"");
+    }
+
+    #[test]
+    fn locates_missing_file_at_its_path() {
+        let text = SCENE.replace(
+            "[materials.white]",
+            "[textures.photo]\ntype = \"image\"\npath = \"missing.png\"\n\n[materials.white]",
+        );
+        let e = parse_error(&text);
+        assert_eq!((e.line, e.column), (9, 8));
+        assert!(e.message.starts_with("failed to load \"missing.png\""));
+    }
+
+    #[test]
+    fn rejects_paths_outside_directory_when_confined() {
+        let text = SCENE.replace(
+            "[materials.white]",
+            "[textures.photo]\ntype = \"image\"\npath = \"../photo.png\"\n\n[materials.white]",
+        );
+        let e = match SceneFile::parse_confined(&text, Path::new("")) {
+            Ok(_) => panic!("the scene should be rejected"),
+            Err(e) => e,
+        };
+        assert_eq!((e.line, e.column), (9, 8));
+        assert_eq!(
+            e.message,
+            "path \"../photo.png\" is outside the scenes directory"
+        );
+    }
+
+    #[test]
+    fn hash_depends_on_referenced_files() {
+        let directory = std::env::temp_dir().join(format!("rtiow-{}-scene", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let write_grid = |value: f32| {
+            let mut bytes = Vec::new();
+            for dim in [1u32, 1, 1] {
+                bytes.extend_from_slice(&dim.to_le_bytes());
+            }
+            bytes.extend_from_slice(&value.to_le_bytes());
+            fs::write(directory.join("grid.raw"), bytes).unwrap();
+        };
+        let text = format!(
+            "{}\n[[objects]]\ntype = \"heterogeneous_medium\"\n\
+             boundary = {{ type = \"sphere\", center = [0, 0, 0], radius = 1, material = \"white\" }}\n\
+             density = {{ type = \"grid\", path = \"grid.raw\", min = [-1, -1, -1], max = [1, 1, 1] }}\n\
+             phase = \"white\"\n",
+            SCENE
+        );
+
+        write_grid(0.5);
+        let first = SceneFile::parse(&text, &directory).unwrap().hash;
+        write_grid(0.5);
+        let same = SceneFile::parse(&text, &directory).unwrap().hash;
+        write_grid(0.25);
+        let changed = SceneFile::parse(&text, &directory).unwrap().hash;
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(first, same);
+        assert_ne!(first, changed);
+    }
+}
@@ -0,0 +1,541 @@
+//! A long-lived local HTTP service rendering jobs one after the other, so that other tools can
+//! drive renders without running the program for each of them. Requests and replies are JSON:
+//!
+//! - `POST /jobs` queues the render described by a `JobRequest`, and returns its status. The
+//!   scene is either one of the scenes directory, given by name, or the text of a scene file
+//! - `GET /jobs` returns the status of every job
+//! - `GET /jobs/<id>` returns the status of a job
+//! - `GET /jobs/<id>/image.png` returns the image of the last pass rendered by a job
+//! - `DELETE /jobs/<id>` cancels a job. A job being rendered stops after its current pass
+//!
+//! Jobs are kept, with their latest image, until the service stops.
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Cursor, Read};
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use image::{ImageOutputFormat, ImageResult};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::integrator::IntegratorKind;
+use crate::render::{render_progressive, AdaptiveSampling, Progress, RenderSettings};
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::scene_file::SceneSettings;
+use crate::tonemap::{OutputTransform, ToneMapper};
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_SIZE: u64 = 1 << 20;
+
+/// Largest image rendered, in pixels.
+const MAX_IMAGE_SIZE: usize = 1 << 26;
+
+/// Where the scene of a job comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SceneSource {
+    /// Name of a scene of the scenes directory
+    Name(String),
+    /// Text of a scene file, whose paths must be within the scenes directory
+    Text(String),
+}
+
+/// Builds the scene of a job, and the camera looking at it, once the job's settings are known.
+pub type SceneBuilder = Box<dyn FnOnce(&RenderSettings) -> (Scene, Camera) + Send>;
+
+/// A render submitted to the service. Settings which aren't given keep the service's defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobRequest {
+    /// Name of the scene to render, from the scenes directory
+    pub scene: Option<String>,
+    /// Text of a scene file to render instead of a named scene
+    pub scene_text: Option<String>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub samples: Option<usize>,
+    pub seed: Option<u64>,
+    pub sampler: Option<SamplerKind>,
+    pub integrator: Option<IntegratorKind>,
+    pub pass_samples: Option<usize>,
+    /// Whether to sample noisy pixels more, with the default parameters
+    pub adaptive: Option<bool>,
+    pub denoise: Option<bool>,
+    /// Time limit of the render, in seconds
+    pub time_limit: Option<f32>,
+    pub noise_target: Option<f32>,
+    /// Exposure compensation of the image, in stops
+    pub exposure: Option<f32>,
+    pub tone_mapper: Option<ToneMapper>,
+}
+
+impl JobRequest {
+    /// Return where the scene to render comes from. Names can't refer to files outside the scenes
+    /// directory.
+    fn source(&self) -> Result<SceneSource, String> {
+        match (&self.scene, &self.scene_text) {
+            (Some(name), None) => {
+                let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+                if name.is_empty() || !name.chars().all(valid) {
+                    return Err(format!("invalid scene name \"{}\"", name));
+                }
+                Ok(SceneSource::Name(name.clone()))
+            }
+            (None, Some(text)) => Ok(SceneSource::Text(text.clone())),
+            _ => Err("either a scene or a scene text must be given".to_string()),
+        }
+    }
+
+    /// Return the settings of the render, starting from `defaults`.
+    fn settings(&self, defaults: &RenderSettings) -> Result<RenderSettings, String> {
+        let mut settings = defaults.clone();
+        settings.width = self.width.unwrap_or(settings.width);
+        settings.height = self.height.unwrap_or(settings.height);
+        settings.samples = self.samples.unwrap_or(settings.samples);
+        settings.seed = self.seed.unwrap_or(settings.seed);
+        settings.sampler = self.sampler.unwrap_or(settings.sampler);
+        settings.integrator = self.integrator.unwrap_or(settings.integrator);
+        settings.pass_samples = self.pass_samples.unwrap_or(settings.pass_samples);
+        if let Some(adaptive) = self.adaptive {
+            settings.adaptive = adaptive.then(AdaptiveSampling::default);
+        }
+        settings.denoise = self.denoise.unwrap_or(settings.denoise);
+        if let Some(time_limit) = self.time_limit {
+            settings.time_limit = Some(
+                Duration::try_from_secs_f32(time_limit)
+                    .map_err(|_| format!("invalid time limit {}", time_limit))?,
+            );
+        }
+        settings.noise_target = self.noise_target.or(settings.noise_target);
+        // Jobs only keep their images in memory
+        settings.checkpoint = None;
+
+        let pixels = settings
+            .width
+            .checked_mul(settings.height)
+            .filter(|&pixels| pixels > 0 && pixels <= MAX_IMAGE_SIZE)
+            .ok_or_else(|| format!("invalid image size {}x{}", settings.width, settings.height))?;
+        if settings.samples == 0 || settings.pass_samples == 0 {
+            return Err("the number of samples must be positive".to_string());
+        }
+        // Sample indices are sent and stored as 32-bit numbers
+        let max_factor = settings.adaptive.map_or(1, |adaptive| adaptive.max_factor);
+        let max_samples = settings.samples.checked_mul(max_factor.max(1));
+        if max_samples.is_none_or(|n| n > u32::MAX as usize)
+            || settings.samples.checked_mul(pixels).is_none()
+        {
+            return Err(format!("too many samples ({})", settings.samples));
+        }
+        if let Some(crop) = &settings.crop {
+            let window = crop.bounds(settings.width, settings.height);
+            if window.width() == 0 || window.height() == 0 {
+                return Err("the crop window is outside the image".to_string());
+            }
+        }
+        Ok(settings)
+    }
+
+    fn transform(&self, defaults: &OutputTransform) -> OutputTransform {
+        OutputTransform {
+            exposure: self.exposure.unwrap_or(defaults.exposure),
+            tone_mapper: self.tone_mapper.unwrap_or(defaults.tone_mapper),
+        }
+    }
+}
+
+/// The stages of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for the jobs before it
+    Queued,
+    Rendering,
+    Done,
+    Cancelled,
+    /// The job couldn't be rendered, see its error
+    Failed,
+}
+
+/// The progress of a job, as sent to clients.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    /// Name of the scene, unless its text was given
+    pub scene: Option<String>,
+    pub state: JobState,
+    /// Why the job failed
+    pub error: Option<String>,
+    /// Number of passes rendered so far
+    pub passes: usize,
+    /// Average number of samples per pixel rendered so far
+    pub samples_per_pixel: f32,
+    /// Average number of samples per pixel the render aims for
+    pub target_samples: usize,
+    /// Time spent rendering, in seconds
+    pub elapsed: f32,
+    /// Estimated time left, in seconds, once the first pass is done
+    pub eta: Option<f32>,
+}
+
+/// A job known to the service.
+struct Job {
+    /// Builds the scene, until the job starts rendering
+    builder: Option<SceneBuilder>,
+    settings: RenderSettings,
+    transform: OutputTransform,
+    status: JobStatus,
+    /// The PNG image of the last pass
+    image: Option<Vec<u8>>,
+    /// Set to stop the render after its current pass
+    cancelled: bool,
+}
+
+/// The jobs, shared between the thread answering requests and the one rendering.
+#[derive(Default)]
+struct Jobs {
+    jobs: BTreeMap<u64, Job>,
+    /// Ids of the jobs waiting to be rendered, in order
+    queue: VecDeque<u64>,
+    next_id: u64,
+}
+
+type SharedJobs = Arc<(Mutex<Jobs>, Condvar)>;
+
+/// Answer requests on `address` until the service fails. Jobs are rendered with `settings` and
+/// written with `transform`, except for what the settings of their scene, then `options`, then
+/// their requests override. `load` loads the scene of a job when it is submitted, and returns
+/// its settings and what builds it, or why it couldn't be loaded.
+pub fn serve<L>(
+    address: &str,
+    settings: RenderSettings,
+    options: SceneSettings,
+    transform: OutputTransform,
+    load: L,
+) -> io::Result<()>
+where
+    L: Fn(&SceneSource) -> Result<(SceneSettings, SceneBuilder), String>,
+{
+    let server = Server::http(address).map_err(io::Error::other)?;
+    info!("Listening on http://{}", server.server_addr());
+
+    let shared = SharedJobs::default();
+    {
+        let shared = shared.clone();
+        thread::spawn(move || render_jobs(&shared));
+    }
+
+    let defaults = Defaults {
+        settings,
+        options,
+        transform,
+    };
+    for mut request in server.incoming_requests() {
+        let response = answer(&shared, &mut request, &defaults, &load);
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to answer request: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// What jobs start from, before the settings of their scene and request.
+struct Defaults {
+    settings: RenderSettings,
+    /// Settings taking precedence over those of the scenes
+    options: SceneSettings,
+    transform: OutputTransform,
+}
+
+/// Render the queued jobs one after the other, forever.
+fn render_jobs(shared: &SharedJobs) {
+    let (lock, queued) = &**shared;
+    loop {
+        let (id, builder, settings, transform) = {
+            let mut jobs = queued
+                .wait_while(lock.lock().unwrap(), |jobs| jobs.queue.is_empty())
+                .unwrap();
+            let id = jobs.queue.pop_front().unwrap();
+            let job = jobs.jobs.get_mut(&id).unwrap();
+            job.status.state = JobState::Rendering;
+            (
+                id,
+                job.builder.take().unwrap(),
+                job.settings.clone(),
+                job.transform,
+            )
+        };
+        info!("Rendering job {}", id);
+
+        // A panicking render only fails its own job
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            render_job(shared, id, builder, &settings, &transform)
+        }));
+        let mut jobs = lock.lock().unwrap();
+        let job = jobs.jobs.get_mut(&id).unwrap();
+        match result {
+            Ok(Ok(())) if job.cancelled => job.status.state = JobState::Cancelled,
+            Ok(Ok(())) => job.status.state = JobState::Done,
+            Ok(Err(e)) => {
+                job.status.state = JobState::Failed;
+                job.status.error = Some(e);
+            }
+            Err(_) => {
+                job.status.state = JobState::Failed;
+                job.status.error = Some("the render panicked".to_string());
+            }
+        }
+        job.status.eta = None;
+        info!("Job {} {:?}", id, job.status.state);
+    }
+}
+
+fn render_job(
+    shared: &SharedJobs,
+    id: u64,
+    builder: SceneBuilder,
+    settings: &RenderSettings,
+    transform: &OutputTransform,
+) -> Result<(), String> {
+    let (scene, camera) = builder(settings);
+
+    let (lock, _) = &**shared;
+    let start = Instant::now();
+    let update = |film: &Film, progress: Option<&Progress>| {
+        let image = encode_png(film, transform);
+        let mut jobs = lock.lock().unwrap();
+        let job = jobs.jobs.get_mut(&id).unwrap();
+        let status = &mut job.status;
+        status.samples_per_pixel = film.mean_sample_count();
+        status.elapsed = start.elapsed().as_secs_f32();
+        if let Some(progress) = progress {
+            status.passes = progress.passes;
+            // Passes which take no samples, e.g. past a noise target, give no estimate
+            if progress.fraction > 0.0 {
+                let elapsed = progress.elapsed.as_secs_f32();
+                status.eta = Some(elapsed * (1.0 - progress.fraction) / progress.fraction);
+            }
+        }
+        match image {
+            Ok(image) => job.image = Some(image),
+            Err(e) => warn!("Failed to encode the image of job {}: {}", id, e),
+        }
+        job.cancelled
+    };
+
+    let film = render_progressive(&scene, &camera, settings, None, |film, progress| {
+        if update(film, Some(progress)) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    });
+    // The final film may be denoised
+    update(&film, None);
+    Ok(())
+}
+
+fn encode_png(film: &Film, transform: &OutputTransform) -> ImageResult<Vec<u8>> {
+    let mut png = Vec::new();
+    film.to_rgb8(transform)
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+/// Return the response to a request. New jobs start from `defaults`, and their scenes are loaded
+/// by `load`.
+fn answer<L>(
+    shared: &SharedJobs,
+    request: &mut Request,
+    defaults: &Defaults,
+    load: &L,
+) -> Response<Cursor<Vec<u8>>>
+where
+    L: Fn(&SceneSource) -> Result<(SceneSettings, SceneBuilder), String>,
+{
+    let url = request.url().to_string();
+    let path: Vec<&str> = url
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect();
+    let (lock, queued) = &**shared;
+
+    match (request.method(), &path[..]) {
+        (Method::Post, ["jobs"]) => {
+            let mut body = String::new();
+            if let Err(e) = request
+                .as_reader()
+                .take(MAX_BODY_SIZE)
+                .read_to_string(&mut body)
+            {
+                return error(400, &format!("failed to read the request: {}", e));
+            }
+            let job_request: JobRequest = match serde_json::from_str(&body) {
+                Ok(job_request) => job_request,
+                Err(e) => return error(400, &format!("invalid job: {}", e)),
+            };
+            let source = match job_request.source() {
+                Ok(source) => source,
+                Err(e) => return error(400, &format!("invalid job: {}", e)),
+            };
+            let (scene_settings, builder) = match load(&source) {
+                Ok(loaded) => loaded,
+                Err(e) => return error(400, &format!("failed to load the scene: {}", e)),
+            };
+            let options = &defaults.options;
+            let settings = options.apply(&scene_settings.apply(&defaults.settings));
+            let settings = match job_request.settings(&settings) {
+                Ok(settings) => settings,
+                Err(e) => return error(400, &format!("invalid job: {}", e)),
+            };
+            let transform = options.transform(&scene_settings.transform(&defaults.transform));
+
+            let mut jobs = lock.lock().unwrap();
+            jobs.next_id += 1;
+            let id = jobs.next_id;
+            let status = JobStatus {
+                id,
+                scene: job_request.scene.clone(),
+                state: JobState::Queued,
+                error: None,
+                passes: 0,
+                samples_per_pixel: 0.0,
+                target_samples: settings.samples,
+                elapsed: 0.0,
+                eta: None,
+            };
+            jobs.jobs.insert(
+                id,
+                Job {
+                    builder: Some(builder),
+                    transform: job_request.transform(&transform),
+                    settings,
+                    status: status.clone(),
+                    image: None,
+                    cancelled: false,
+                },
+            );
+            jobs.queue.push_back(id);
+            queued.notify_one();
+            info!("Queued job {}", id);
+            json(201, &status)
+        }
+        (Method::Get, ["jobs"]) => {
+            let jobs = lock.lock().unwrap();
+            let statuses: Vec<&JobStatus> = jobs.jobs.values().map(|job| &job.status).collect();
+            json(200, &statuses)
+        }
+        (method, ["jobs", id, rest @ ..]) => {
+            let id = match id.parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => return error(404, "no such job"),
+            };
+            let mut jobs = lock.lock().unwrap();
+            let jobs = &mut *jobs;
+            let job = match jobs.jobs.get_mut(&id) {
+                Some(job) => job,
+                None => return error(404, "no such job"),
+            };
+            match (method, rest) {
+                (Method::Get, []) => json(200, &job.status),
+                (Method::Get, ["image.png"]) => match &job.image {
+                    Some(image) => {
+                        Response::from_data(image.clone()).with_header(content_type("image/png"))
+                    }
+                    None => error(404, "no pass has been rendered yet"),
+                },
+                (Method::Delete, []) => match job.status.state {
+                    JobState::Queued => {
+                        jobs.queue.retain(|&queued| queued != id);
+                        job.status.state = JobState::Cancelled;
+                        info!("Cancelled job {}", id);
+                        json(200, &job.status)
+                    }
+                    JobState::Rendering => {
+                        job.cancelled = true;
+                        info!("Cancelling job {}", id);
+                        json(202, &job.status)
+                    }
+                    _ => error(409, "the job has already finished"),
+                },
+                (_, []) | (_, ["image.png"]) => error(405, "method not allowed"),
+                _ => error(404, "not found"),
+            }
+        }
+        (_, ["jobs"]) => error(405, "method not allowed"),
+        _ => error(404, "not found"),
+    }
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).unwrap()
+}
+
+fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(serde_json::to_vec(value).unwrap())
+        .with_status_code(status)
+        .with_header(content_type("application/json"))
+}
+
+fn error(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(json: &str) -> JobRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn scenes_are_named_or_given_as_text() {
+        let named = request(r#"{ "scene": "cornell_box" }"#);
+        assert_eq!(
+            named.source(),
+            Ok(SceneSource::Name("cornell_box".to_string()))
+        );
+        let text = request(r#"{ "scene_text": "[camera]" }"#);
+        assert_eq!(text.source(), Ok(SceneSource::Text("[camera]".to_string())));
+        for json in [
+            r#"{ "scene": "../secret" }"#,
+            r#"{ "scene": "/etc/passwd" }"#,
+            r#"{ "scene": "" }"#,
+            r#"{}"#,
+            r#"{ "scene": "a", "scene_text": "[camera]" }"#,
+        ] {
+            assert!(request(json).source().is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn oversized_jobs_are_rejected() {
+        let defaults = RenderSettings {
+            adaptive: Some(AdaptiveSampling::default()),
+            ..RenderSettings::default()
+        };
+        let settings = |json: &str| request(json).settings(&defaults);
+        assert!(settings(r#"{ "width": 64, "height": 32, "samples": 16 }"#).is_ok());
+        for json in [
+            r#"{ "width": 0 }"#,
+            r#"{ "width": 4294967296, "height": 4294967296 }"#,
+            r#"{ "width": 16384, "height": 16384 }"#,
+            r#"{ "samples": 0 }"#,
+            r#"{ "samples": 18446744073709551615 }"#,
+            r#"{ "samples": 4294967296, "adaptive": false }"#,
+            r#"{ "samples": 1000000000 }"#,
+        ] {
+            assert!(settings(json).is_err(), "{}", json);
+        }
+    }
+}
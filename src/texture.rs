@@ -1,15 +1,18 @@
 use std::f32;
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
 use std::sync::Arc;
 
+use image::codecs::hdr::HdrDecoder;
 use image::{self, GenericImageView};
-use log::{info, warn};
+use log::info;
 
 use crate::perlin;
 use crate::vec::Vec3;
 
-pub trait Texture: Debug {
+pub trait Texture: Debug + Send + Sync {
     fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
 }
 
@@ -81,18 +84,12 @@ pub struct ImageTexture {
 }
 
 impl ImageTexture {
-    pub fn new<P: AsRef<Path>>(filename: P) -> ImageTexture {
+    /// Load the texture from an image file.
+    pub fn open<P: AsRef<Path>>(filename: P) -> image::ImageResult<ImageTexture> {
         let filename = filename.as_ref();
-        let (img, nx, ny) = match image::open(filename) {
-            Ok(data) => {
-                let (nx, ny) = data.dimensions();
-                (data.to_rgb8().into_raw().into_boxed_slice(), nx, ny)
-            }
-            Err(e) => {
-                warn!("Failed to open image {}: {}", filename.display(), e);
-                (vec![128, 128, 128].into_boxed_slice(), 1, 1)
-            }
-        };
+        let data = image::open(filename)?;
+        let (nx, ny) = data.dimensions();
+        let img = data.to_rgb8().into_raw().into_boxed_slice();
         info!(
             "Loaded texture {} with size {}x{}",
             filename.display(),
@@ -100,7 +97,7 @@ impl ImageTexture {
             ny
         );
 
-        ImageTexture { data: img, nx, ny }
+        Ok(ImageTexture { data: img, nx, ny })
     }
 }
 
@@ -129,3 +126,71 @@ impl Texture for ImageTexture {
         Vec3::new(r, g, b)
     }
 }
+
+/// Texture backed by a floating point image, for high dynamic range formats such as Radiance HDR
+/// or OpenEXR. Unlike `ImageTexture`, values are kept linear and aren't clamped to [0, 1].
+#[derive(Debug)]
+pub struct HdrImageTexture {
+    pub nx: u32,
+    pub ny: u32,
+    pub data: Box<[Vec3]>,
+}
+
+impl HdrImageTexture {
+    /// Load the texture from an image file.
+    pub fn open<P: AsRef<Path>>(filename: P) -> image::ImageResult<HdrImageTexture> {
+        let filename = filename.as_ref();
+        let (data, nx, ny) = load_hdr_image(filename)?;
+        info!(
+            "Loaded HDR texture {} with size {}x{}",
+            filename.display(),
+            nx,
+            ny
+        );
+
+        Ok(HdrImageTexture { nx, ny, data })
+    }
+
+    /// Return the pixel at column `i` and row `j`, where row 0 is the top of the image.
+    pub fn pixel(&self, i: u32, j: u32) -> Vec3 {
+        self.data[(i + self.nx * j) as usize]
+    }
+}
+
+/// Load an image as linear floating point pixels, with its width and height.
+fn load_hdr_image(filename: &Path) -> image::ImageResult<(Box<[Vec3]>, u32, u32)> {
+    let is_hdr = filename
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("hdr"));
+    if is_hdr {
+        // The generic decoder converts Radiance HDR images to 8 bits, so use the dedicated one
+        let decoder = HdrDecoder::new(BufReader::new(File::open(filename)?))?;
+        let metadata = decoder.metadata();
+        let data = decoder
+            .read_image_hdr()?
+            .iter()
+            .map(|p| Vec3::new(p[0], p[1], p[2]))
+            .collect();
+        return Ok((data, metadata.width, metadata.height));
+    }
+
+    let img = image::open(filename)?;
+    let (nx, ny) = img.dimensions();
+    let data = img
+        .to_rgb32f()
+        .pixels()
+        .map(|p| Vec3::new(p[0], p[1], p[2]))
+        .collect();
+
+    Ok((data, nx, ny))
+}
+
+impl Texture for HdrImageTexture {
+    fn value(&self, u: f32, v: f32, _p: &Vec3) -> Vec3 {
+        let i = (u * self.nx as f32).clamp(0.0, (self.nx - 1) as f32) as u32;
+        let j = (v * self.ny as f32).clamp(0.0, (self.ny - 1) as f32) as u32;
+
+        self.pixel(i, j)
+    }
+}
@@ -0,0 +1,147 @@
+//! Transforms from the linear radiance stored in the film to the colours of 8-bit images.
+use std::f32;
+
+use serde::Deserialize;
+
+use crate::vec::Vec3;
+
+/// Operators compressing the radiance to [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapper {
+    /// Clamp each channel to [0, 1]
+    #[default]
+    Clamp,
+    /// `c / (1 + c)` on each channel, which never reaches white
+    Reinhard,
+    /// Reinhard's operator extended so that `white` maps to 1
+    ExtendedReinhard { white: f32 },
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+impl ToneMapper {
+    pub fn map(&self, c: Vec3) -> Vec3 {
+        let map_channel = |x: f32| match *self {
+            ToneMapper::Clamp => x,
+            ToneMapper::Reinhard => x / (1.0 + x),
+            ToneMapper::ExtendedReinhard { white } => x * (1.0 + x / (white * white)) / (1.0 + x),
+            ToneMapper::Aces => {
+                let x = 0.6 * x;
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }
+            ToneMapper::Hable => {
+                const WHITE: f32 = 11.2;
+                const EXPOSURE_BIAS: f32 = 2.0;
+                hable_partial(EXPOSURE_BIAS * x) / hable_partial(WHITE)
+            }
+        };
+
+        Vec3::new(map_channel(c.r()), map_channel(c.g()), map_channel(c.b()))
+    }
+}
+
+fn hable_partial(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+/// The full transform applied to the film when writing 8-bit images: exposure, tone mapping, and
+/// the sRGB transfer function.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OutputTransform {
+    /// Exposure compensation, in stops: each unit doubles the brightness
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+}
+
+impl OutputTransform {
+    /// Return the 8-bit sRGB colour of the given linear radiance.
+    pub fn apply(&self, c: &Vec3) -> [u8; 3] {
+        let c = f32::exp2(self.exposure) * c;
+        let c = self.tone_mapper.map(c);
+        let mut rgb = [0; 3];
+        for (i, v) in rgb.iter_mut().enumerate() {
+            let x = if c[i].is_nan() { 0.0 } else { c[i] };
+            *v = (255.0 * srgb_oetf(x.clamp(0.0, 1.0)) + 0.5) as u8;
+        }
+
+        rgb
+    }
+}
+
+/// The sRGB opto-electronic transfer function, encoding a linear value in [0, 1].
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * f32::powf(x, 1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPPERS: [ToneMapper; 5] = [
+        ToneMapper::Clamp,
+        ToneMapper::Reinhard,
+        ToneMapper::ExtendedReinhard { white: 4.0 },
+        ToneMapper::Aces,
+        ToneMapper::Hable,
+    ];
+
+    fn map(mapper: ToneMapper, x: f32) -> f32 {
+        mapper.map(Vec3::new(x, x, x)).r()
+    }
+
+    #[test]
+    fn mappers_are_increasing_from_black() {
+        for mapper in MAPPERS {
+            assert!(map(mapper, 0.0).abs() < 1e-6, "{:?}", mapper);
+            let values: Vec<f32> = (0..100).map(|i| map(mapper, 0.05 * i as f32)).collect();
+            assert!(values.windows(2).all(|w| w[0] <= w[1]), "{:?}", mapper);
+        }
+    }
+
+    #[test]
+    fn mappers_reach_white_where_expected() {
+        assert!(map(ToneMapper::Reinhard, 1000.0) < 1.0);
+        assert!((map(ToneMapper::ExtendedReinhard { white: 4.0 }, 4.0) - 1.0).abs() < 1e-6);
+        // Hable's curve is scaled so that 11.2 maps to white, after an exposure bias of 2
+        assert!((map(ToneMapper::Hable, 5.6) - 1.0).abs() < 1e-6);
+        assert!((map(ToneMapper::Aces, 100.0) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn srgb_transfer_function_is_continuous() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+        let threshold = 0.003_130_8;
+        assert!((srgb_oetf(threshold) - srgb_oetf(threshold + 1e-7)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn output_transform_applies_exposure_and_clamps() {
+        let transform = OutputTransform::default();
+        let brighter = OutputTransform {
+            exposure: 1.0,
+            ..transform
+        };
+        let c = Vec3::new(0.1, 0.2, 0.3);
+        assert_eq!(brighter.apply(&c), transform.apply(&(2.0 * c)));
+        assert_eq!(transform.apply(&Vec3::new(0.0, 1.0, 5.0)), [0, 255, 255]);
+        assert_eq!(
+            transform.apply(&Vec3::new(f32::NAN, -1.0, 0.5)),
+            [0, 0, 188]
+        );
+    }
+}
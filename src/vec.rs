@@ -2,11 +2,21 @@
 //! direction, colour, etc...
 use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Neg, Sub, SubAssign};
 
-#[derive(Debug, PartialEq, PartialOrd, Default, Clone, Copy)]
+use serde::Deserialize;
+
+/// Deserialized from an array of its 3 components.
+#[derive(Debug, PartialEq, PartialOrd, Default, Clone, Copy, Deserialize)]
+#[serde(from = "[f32; 3]")]
 pub struct Vec3 {
     e: [f32; 3],
 }
 
+impl From<[f32; 3]> for Vec3 {
+    fn from(e: [f32; 3]) -> Vec3 {
+        Vec3 { e }
+    }
+}
+
 impl Vec3 {
     pub fn new(e0: f32, e1: f32, e2: f32) -> Vec3 {
         Vec3 { e: [e0, e1, e2] }
@@ -52,6 +62,17 @@ impl Vec3 {
         self.e[0] * self.e[0] + self.e[1] * self.e[1] + self.e[2] * self.e[2]
     }
 
+    /// Return the luminance of this vector, interpreted as a linear sRGB colour.
+    #[inline]
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.e[0] + 0.7152 * self.e[1] + 0.0722 * self.e[2]
+    }
+
+    #[inline]
+    pub fn max_component(&self) -> f32 {
+        f32::max(self.e[0], f32::max(self.e[1], self.e[2]))
+    }
+
     #[inline]
     pub fn make_unit_vector(&mut self) {
         let k = self.length();
@@ -87,7 +108,7 @@ impl Index<usize> for Vec3 {
     }
 }
 
-impl<'a> Neg for &'a Vec3 {
+impl Neg for &Vec3 {
     type Output = Vec3;
 
     fn neg(self) -> Vec3 {
@@ -103,7 +124,7 @@ impl Neg for Vec3 {
     }
 }
 
-impl<'a> Add<f32> for &'a Vec3 {
+impl Add<f32> for &Vec3 {
     type Output = Vec3;
 
     fn add(self, v: f32) -> Vec3 {
@@ -111,7 +132,7 @@ impl<'a> Add<f32> for &'a Vec3 {
     }
 }
 
-impl<'a> Sub<f32> for &'a Vec3 {
+impl Sub<f32> for &Vec3 {
     type Output = Vec3;
 
     fn sub(self, v: f32) -> Vec3 {
@@ -127,7 +148,7 @@ impl Sub<Vec3> for Vec3 {
     }
 }
 
-impl<'a, 'b> Sub<&'b Vec3> for &'a Vec3 {
+impl<'b> Sub<&'b Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn sub(self, v: &'b Vec3) -> Vec3 {
@@ -143,7 +164,7 @@ impl Mul<Vec3> for Vec3 {
     }
 }
 
-impl<'a> Mul<f32> for &'a Vec3 {
+impl Mul<f32> for &Vec3 {
     type Output = Vec3;
 
     fn mul(self, v: f32) -> Vec3 {
@@ -161,7 +182,7 @@ impl Div<f32> for Vec3 {
     }
 }
 
-impl<'a> Div<f32> for &'a Vec3 {
+impl Div<f32> for &Vec3 {
     type Output = Vec3;
 
     fn div(self, v: f32) -> Vec3 {
@@ -221,7 +242,7 @@ impl Add<Vec3> for Vec3 {
     }
 }
 
-impl<'a> Add<Vec3> for &'a Vec3 {
+impl Add<Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn add(self, v: Vec3) -> Vec3 {
@@ -229,7 +250,7 @@ impl<'a> Add<Vec3> for &'a Vec3 {
     }
 }
 
-impl<'a, 'b> Add<&'a Vec3> for &'b Vec3 {
+impl<'a> Add<&'a Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn add(self, v: &'a Vec3) -> Vec3 {